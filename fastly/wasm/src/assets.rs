@@ -0,0 +1,213 @@
+use fastly::Request;
+use fastly::http::Method;
+use fastly::http::header::ACCEPT;
+
+const SEC_FETCH_DEST_HEADER: &str = "sec-fetch-dest";
+const SEC_PURPOSE_HEADER: &str = "sec-purpose";
+const PURPOSE_HEADER: &str = "purpose";
+
+/// Kind of static asset, used to decide which requests skip bot detection.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AssetClass {
+    Script,
+    Style,
+    Image,
+    Font,
+    Media,
+    Manifest,
+    Worker,
+    Object,
+    /// Extension from `static_extensions` config.
+    Other,
+    /// Path starting with one of `static_path_prefixes`.
+    Prefix,
+    /// Speculative prefetch, scripts don't run in prefetched pages.
+    Prefetch
+}
+
+const ALL_CLASSES: [AssetClass; 11] = [
+    AssetClass::Script,
+    AssetClass::Style,
+    AssetClass::Image,
+    AssetClass::Font,
+    AssetClass::Media,
+    AssetClass::Manifest,
+    AssetClass::Worker,
+    AssetClass::Object,
+    AssetClass::Other,
+    AssetClass::Prefix,
+    AssetClass::Prefetch
+];
+
+/// Classes which skip bot detection by default. Prefetch is left out, as its header is easy to spoof.
+const DEFAULT_SKIP_CLASSES: [AssetClass; 10] = [
+    AssetClass::Script,
+    AssetClass::Style,
+    AssetClass::Image,
+    AssetClass::Font,
+    AssetClass::Media,
+    AssetClass::Manifest,
+    AssetClass::Worker,
+    AssetClass::Object,
+    AssetClass::Other,
+    AssetClass::Prefix
+];
+
+/// Path endings of static files. Generic data formats like `.json` or `.xml` are left out,
+/// API routes use them too.
+const EXTENSIONS: [(&str, AssetClass); 31] = [
+    (".js", AssetClass::Script),
+    (".mjs", AssetClass::Script),
+    (".map", AssetClass::Script),
+    (".wasm", AssetClass::Script),
+    (".css", AssetClass::Style),
+    (".jpg", AssetClass::Image),
+    (".jpeg", AssetClass::Image),
+    (".png", AssetClass::Image),
+    (".gif", AssetClass::Image),
+    (".svg", AssetClass::Image),
+    (".webp", AssetClass::Image),
+    (".avif", AssetClass::Image),
+    (".ico", AssetClass::Image),
+    (".bmp", AssetClass::Image),
+    (".woff", AssetClass::Font),
+    (".woff2", AssetClass::Font),
+    (".ttf", AssetClass::Font),
+    (".otf", AssetClass::Font),
+    (".eot", AssetClass::Font),
+    (".mp4", AssetClass::Media),
+    (".webm", AssetClass::Media),
+    (".ogg", AssetClass::Media),
+    (".mp3", AssetClass::Media),
+    (".wav", AssetClass::Media),
+    (".m4a", AssetClass::Media),
+    (".m3u8", AssetClass::Media),
+    (".vtt", AssetClass::Media),
+    (".webmanifest", AssetClass::Manifest),
+    ("/manifest.json", AssetClass::Manifest),
+    (".pdf", AssetClass::Object),
+    (".zip", AssetClass::Object),
+    (".swf", AssetClass::Object)
+];
+
+impl AssetClass {
+    pub fn parse(value: &str) -> Option<Self> {
+        ALL_CLASSES.iter().copied().find(|c| c.name() == value)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AssetClass::Script => "script",
+            AssetClass::Style => "style",
+            AssetClass::Image => "image",
+            AssetClass::Font => "font",
+            AssetClass::Media => "media",
+            AssetClass::Manifest => "manifest",
+            AssetClass::Worker => "worker",
+            AssetClass::Object => "object",
+            AssetClass::Other => "other",
+            AssetClass::Prefix => "prefix",
+            AssetClass::Prefetch => "prefetch"
+        }
+    }
+
+    fn from_sec_fetch_dest(dest: &str) -> Option<Self> {
+        match dest {
+            "script" | "audioworklet" | "paintworklet" => Some(AssetClass::Script),
+            "style" | "xslt" => Some(AssetClass::Style),
+            "image" => Some(AssetClass::Image),
+            "font" => Some(AssetClass::Font),
+            "audio" | "video" | "track" => Some(AssetClass::Media),
+            "manifest" => Some(AssetClass::Manifest),
+            "worker" | "sharedworker" | "serviceworker" => Some(AssetClass::Worker),
+            "object" | "embed" => Some(AssetClass::Object),
+            _ => None
+        }
+    }
+}
+
+/// Rules of static asset classification.
+pub struct AssetConfig {
+    /// Additional path extensions, classified as `other`.
+    pub extensions: Vec<String>,
+    pub path_prefixes: Vec<String>,
+    /// Classes which skip bot detection.
+    pub skip: Vec<AssetClass>
+}
+
+impl AssetConfig {
+    pub fn new(extensions: Vec<String>, path_prefixes: Vec<String>, skip: Option<Vec<String>>) -> Self {
+        let skip = match skip {
+            Some(names) => names.iter().filter_map(|n| {
+                let class = AssetClass::parse(n);
+                if class.is_none() {
+                    log::error!("[assets] Unknown asset class {}, ignored", n);
+                }
+                class
+            }).collect(),
+            _ => DEFAULT_SKIP_CLASSES.to_vec()
+        };
+        let extensions = extensions.into_iter()
+            .map(|e| if e.starts_with('.') { e } else { format!(".{}", e) })
+            .collect();
+        AssetConfig { extensions, path_prefixes, skip }
+    }
+}
+
+fn is_prefetch(req: &Request) -> bool {
+    if req.get_method() != Method::GET {
+        return false;
+    }
+    // `Sec-Purpose: prefetch` or `prefetch;prerender`, older browsers send `Purpose: prefetch`
+    [SEC_PURPOSE_HEADER, PURPOSE_HEADER].iter()
+        .filter_map(|h| req.get_header_str(*h))
+        .any(|p| p.trim().to_lowercase().starts_with("prefetch"))
+}
+
+/// Classifies the request as a static asset, `None` means a page or API request.
+pub fn classify(req: &Request, config: &AssetConfig) -> Option<AssetClass> {
+    let path = req.get_path().to_lowercase();
+    if config.path_prefixes.iter().any(|p| path.starts_with(p.as_str())) {
+        return Some(AssetClass::Prefix);
+    }
+    if is_prefetch(req) {
+        return Some(AssetClass::Prefetch);
+    }
+    // sec-fetch-dest header shows which content was requested, but it works not in all web-browsers
+    if let Some(dest) = req.get_header_str(SEC_FETCH_DEST_HEADER) {
+        return AssetClass::from_sec_fetch_dest(dest.trim().to_lowercase().as_str());
+    }
+    // sec-fetch-dest header doesn't exist => check by path ending
+    if let Some((_, class)) = EXTENSIONS.iter().find(|(e, _)| path.ends_with(e)) {
+        return Some(*class);
+    }
+    if config.extensions.iter().any(|e| path.ends_with(e.as_str())) {
+        return Some(AssetClass::Other);
+    }
+    None
+}
+
+pub fn is_static_requested(req: &Request, config: &AssetConfig) -> bool {
+    match classify(req, config) {
+        Some(class) => {
+            log::debug!("[assets] Static request of class: {}", class.name());
+            config.skip.contains(&class)
+        },
+        _ => false
+    }
+}
+
+/// Top-level page load: `Sec-Fetch-Dest: document`, or HTML `Accept` in browsers without fetch metadata.
+pub fn is_navigation_requested(req: &Request) -> bool {
+    if req.get_method() != Method::GET {
+        return false;
+    }
+    if let Some(dest) = req.get_header_str(SEC_FETCH_DEST_HEADER) {
+        return dest.trim().eq_ignore_ascii_case("document");
+    }
+    req.get_header_str(ACCEPT).map_or(false, |a| a.to_lowercase().contains("text/html"))
+}
+
+pub fn is_favicon_requested(req: &Request, config: &AssetConfig) -> bool {
+    classify(req, config).is_some() && req.get_path().ends_with(".ico")
+}
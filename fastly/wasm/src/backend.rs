@@ -0,0 +1,136 @@
+use std::cmp::min;
+use std::io::Read;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use fastly::{Request, Response};
+use fastly::http::request::{PendingRequest, PollResult};
+use crate::config::{Config, BOTD_BACKEND_NAME};
+use crate::error::BotdError;
+use BotdError::{BodyRead, BudgetExhausted, SendError, Timeout};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+const RETRY_BASE_DELAY_MS: u64 = 50;
+const BODY_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Total time which bot detection may take during a single client request.
+pub struct Budget {
+    started: Instant,
+    total: Duration
+}
+
+impl Budget {
+    pub fn new(total_ms: u64) -> Self {
+        Budget { started: Instant::now(), total: Duration::from_millis(total_ms) }
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.total.checked_sub(self.started.elapsed()).unwrap_or_default()
+    }
+}
+
+fn jitter_ms(max: u64) -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(t) => t.subsec_nanos() as u64 % (max + 1),
+        Err(_) => 0
+    }
+}
+
+fn wait_until(mut pending: PendingRequest, sent: Instant, deadline: Instant) -> Result<Response, BotdError> {
+    loop {
+        pending = match pending.poll() {
+            PollResult::Done(r) => return r.map_err(|e| SendError(Box::new(e))),
+            PollResult::Pending(p) => p
+        };
+        if Instant::now() >= deadline {
+            return Err(Timeout(sent.elapsed().as_millis() as u64));
+        }
+        sleep(POLL_INTERVAL);
+    }
+}
+
+/// Buffers the response body, so that the deadline covers the body and not only the headers.
+/// A single read which stalls is limited by the between bytes timeout of the botd backend.
+fn read_body_until(mut resp: Response, sent: Instant, deadline: Instant) -> Result<Response, BotdError> {
+    let mut body = resp.take_body();
+    let mut bytes = Vec::new();
+    let mut chunk = [0; BODY_CHUNK_SIZE];
+    loop {
+        let read = body.read(&mut chunk).map_err(|e| BodyRead(e.to_string()))?;
+        if read == 0 {
+            return Ok(resp.with_body(bytes));
+        }
+        bytes.extend_from_slice(&chunk[..read]);
+        if Instant::now() >= deadline {
+            return Err(Timeout(sent.elapsed().as_millis() as u64));
+        }
+    }
+}
+
+/// Request to botd which is already sent, but whose response isn't awaited yet.
+pub struct PendingBotdRequest {
+    req: Request,
+    pending: Result<PendingRequest, BotdError>,
+    started: Instant,
+    idempotent: bool
+}
+
+/// Sends request to botd without waiting for the response,
+/// so that other requests can be done in the meantime.
+pub fn send_async_to_botd(mut req: Request, idempotent: bool) -> PendingBotdRequest {
+    let started = Instant::now();
+    let pending = req.clone_with_body()
+        .send_async(BOTD_BACKEND_NAME)
+        .map_err(|e| SendError(Box::new(e)));
+    PendingBotdRequest { req, pending, started, idempotent }
+}
+
+/// Sends request to botd and waits for the response, see [`PendingBotdRequest::wait`].
+pub fn send_to_botd(req: Request, config: &Config, idempotent: bool) -> Result<Response, BotdError> {
+    send_async_to_botd(req, idempotent).wait(config)
+}
+
+impl PendingBotdRequest {
+    /// Waits for the response respecting first byte and total timeouts and the detection budget.
+    /// The total timeout and the budget cover reading the body as well.
+    /// Idempotent requests are retried with exponential backoff and jitter on errors and 5xx responses.
+    pub fn wait(mut self, config: &Config) -> Result<Response, BotdError> {
+        let total_deadline = self.started + Duration::from_millis(config.botd_total_timeout_ms);
+        let first_byte_timeout = Duration::from_millis(config.botd_first_byte_timeout_ms);
+        let attempts = if self.idempotent { config.botd_retries + 1 } else { 1 };
+        let mut sent = self.started;
+        let mut first_pending = Some(self.pending);
+        let mut last_result = Err(BudgetExhausted);
+        for attempt in 0..attempts {
+            let pending = match first_pending.take() {
+                Some(p) => p,
+                _ => {
+                    let base = RETRY_BASE_DELAY_MS << (attempt - 1);
+                    let delay = Duration::from_millis(base + jitter_ms(base));
+                    log::debug!("[backend] Retry #{} to botd in {} ms", attempt, delay.as_millis());
+                    if delay >= config.budget.remaining() || Instant::now() + delay >= total_deadline {
+                        break;
+                    }
+                    sleep(delay);
+                    sent = Instant::now();
+                    self.req.clone_with_body()
+                        .send_async(BOTD_BACKEND_NAME)
+                        .map_err(|e| SendError(Box::new(e)))
+                }
+            };
+            let deadline = min(min(sent + first_byte_timeout, total_deadline), Instant::now() + config.budget.remaining());
+            last_result = pending.and_then(|p| wait_until(p, sent, deadline));
+            match last_result {
+                Ok(r) if !r.get_status().is_server_error() => {
+                    let body_deadline = min(total_deadline, Instant::now() + config.budget.remaining());
+                    return read_body_until(r, sent, body_deadline);
+                },
+                Ok(ref r) => log::error!("[backend] Botd responded with {}", r.get_status()),
+                Err(ref e) => log::error!("[backend] {}", e.to_string())
+            }
+        }
+        if config.budget.remaining().as_millis() == 0 {
+            return Err(BudgetExhausted);
+        }
+        last_result
+    }
+}
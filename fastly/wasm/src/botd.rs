@@ -1,34 +1,55 @@
 use fastly::Request;
 use fastly::http::Method;
-use BotdError::SendError;
-use crate::config::{Config, BOTD_BACKEND_NAME};
-use crate::detector::{Detect, check_botd_resp, transfer_headers};
+use crate::config::Config;
+use crate::detector::{Detect, PendingDetect, transfer_headers};
 use crate::request_id::RequestId;
 use crate::error::BotdError;
 use crate::CLIENT_IP_HEADER;
+use crate::redact::redact_token;
+use crate::backend::{send_async_to_botd, send_to_botd};
+use crate::cache::{cache_fresh_request, cache_results_request, get_age, is_stale_negative};
+use crate::protocol::read_results;
 
 pub struct BotDetector {
     pub req_id: String,
 }
 
-impl Detect for BotDetector {
-    fn make(req: &mut Request, config: &Config) -> Result<Self, BotdError> {
-        let req_id = RequestId::from_req_cookie(req)?;
-        let query = format!("header&token={}&id={}", config.token.to_owned(), req_id);
-        log::debug!("[botd] Make bot detect with request_id: {} and query: ?{}", req_id, query);
-        match req
-            .clone_without_body()
+impl BotDetector {
+    fn results_request(req: &Request, config: &Config, req_id: &str) -> Request {
+        let mut results_req = req.clone_without_body()
             .with_method(Method::GET)
-            .with_path("/api/v1/results")
-            .with_query_str(query)
-            .with_header(CLIENT_IP_HEADER, config.ip.to_owned())
-            .send(BOTD_BACKEND_NAME) {
-            Ok(r) => {
-                check_botd_resp(&r)?;
-                transfer_headers(req, &r);
-                Ok(BotDetector { req_id })
-            }
-            Err(e) => Err(SendError(Box::new(e)))
+            .with_path(&config.api_version.path("results"))
+            .with_query_str(config.protocol.query())
+            .with_header(CLIENT_IP_HEADER, config.ip.to_owned());
+        results_req.get_url_mut().query_pairs_mut()
+            .append_pair("token", &config.token)
+            .append_pair("id", req_id);
+        results_req
+    }
+}
+
+impl Detect for BotDetector {
+    fn start(req: &Request, config: &Config) -> Result<PendingDetect, BotdError> {
+        let req_id = RequestId::from_req(req, config)?;
+        log::debug!("[botd] Make bot detect with request_id: {} and token: {}", req_id, redact_token(&config.token));
+        let mut results_req = Self::results_request(req, config, &req_id);
+        cache_results_request(&mut results_req, config);
+        let request = send_async_to_botd(results_req, true);
+        Ok(PendingDetect { req_id: Some(req_id), request })
+    }
+
+    fn wait(pending: PendingDetect, req: &mut Request, config: &Config) -> Result<Self, BotdError> {
+        let req_id = pending.req_id.unwrap_or_default();
+        let r = pending.request.wait(config)?;
+        let age = get_age(&r);
+        let mut results = read_results(r, config.protocol)?;
+        if is_stale_negative(&results.status, age, config) {
+            log::debug!("[botd] Cached result is stale, looking up again");
+            let mut results_req = Self::results_request(req, config, &req_id);
+            cache_fresh_request(&mut results_req, config);
+            results = read_results(send_to_botd(results_req, config, true)?, config.protocol)?;
         }
+        transfer_headers(req, &results);
+        Ok(BotDetector { req_id })
     }
 }
\ No newline at end of file
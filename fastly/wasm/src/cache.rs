@@ -0,0 +1,140 @@
+use fastly::{Request, Response};
+use fastly::http::header::AGE;
+use crate::PROCESSED_STATUS;
+use crate::config::Config;
+use crate::utils::get_timestamp_ms;
+
+/// Query parameter which makes a separate cache key for the fresh lookup of a negative result.
+const BUCKET_PARAM: &str = "cache_bucket";
+
+/// Makes the results request cacheable at the edge. The cache key is the request URL,
+/// which contains both token and request id.
+pub fn cache_results_request(req: &mut Request, config: &Config) {
+    if config.results_cache_ttl == 0 {
+        return;
+    }
+    log::debug!("[cache] Results cached for {} s", config.results_cache_ttl);
+    req.set_ttl(config.results_cache_ttl);
+}
+
+/// Makes the fresh lookup of a stale negative result cacheable under a key which changes every
+/// `results_cache_negative_ttl` seconds. So botd is called at most once per that period for the
+/// request id, and a processed result is used instead of the negative one from the next period on.
+pub fn cache_fresh_request(req: &mut Request, config: &Config) {
+    let now = (get_timestamp_ms().max(0) / 1000) as u64;
+    let ttl = negative_bucket_ttl(config.results_cache_negative_ttl);
+    let query = format!("{}&{}={}", req.get_query_str().unwrap_or_default(), BUCKET_PARAM, bucket(now, ttl));
+    req.set_query_str(query);
+    log::debug!("[cache] Fresh results cached for {} s", ttl);
+    req.set_ttl(ttl);
+}
+
+fn negative_bucket_ttl(negative_ttl: u32) -> u32 {
+    negative_ttl.max(1)
+}
+
+fn bucket(now: u64, ttl: u32) -> u64 {
+    now / ttl as u64
+}
+
+pub fn get_age(resp: &Response) -> u32 {
+    resp.get_header_str(AGE)
+        .and_then(|a| a.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Results which aren't processed yet (e.g. the agent hasn't sent its data) must not be reused
+/// for longer than `results_cache_negative_ttl`, they have to be looked up again with `cache_fresh_request`.
+pub fn is_stale_negative(status: &str, age: u32, config: &Config) -> bool {
+    if config.results_cache_ttl == 0 {
+        return false;
+    }
+    let is_negative = status != PROCESSED_STATUS;
+    if is_negative {
+        log::debug!("[cache] Negative result, age: {} s", age);
+    }
+    is_stale(is_negative, age, config.results_cache_negative_ttl)
+}
+
+fn is_stale(is_negative: bool, age: u32, negative_ttl: u32) -> bool {
+    is_negative && age > negative_ttl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const TTL: u32 = 300;
+    const NEGATIVE_TTL: u32 = 5;
+
+    /// In-memory stand-in for the edge cache in front of botd, keyed by URL.
+    struct FakeCache {
+        entries: HashMap<String, (bool, u64)>,
+        botd_calls: u32,
+        /// Time the agent has sent its data, results are processed after it.
+        processed_at: u64
+    }
+
+    impl FakeCache {
+        fn new(processed_at: u64) -> Self {
+            FakeCache { entries: HashMap::new(), botd_calls: 0, processed_at }
+        }
+
+        /// Returns whether the result is processed and its age.
+        fn lookup(&mut self, key: String, ttl: u32, now: u64) -> (bool, u32) {
+            if let Some((processed, stored)) = self.entries.get(&key) {
+                if now - stored < ttl as u64 {
+                    return (*processed, (now - stored) as u32);
+                }
+            }
+            self.botd_calls += 1;
+            let processed = now >= self.processed_at;
+            self.entries.insert(key, (processed, now));
+            (processed, 0)
+        }
+
+        /// Same decisions as `BotDetector::wait`.
+        fn results(&mut self, now: u64) -> bool {
+            let (processed, age) = self.lookup(String::from("id"), TTL, now);
+            if !is_stale(!processed, age, NEGATIVE_TTL) {
+                return processed;
+            }
+            let ttl = negative_bucket_ttl(NEGATIVE_TTL);
+            self.lookup(format!("id&{}={}", BUCKET_PARAM, bucket(now, ttl)), ttl, now).0
+        }
+    }
+
+    #[test]
+    fn processed_result_replaces_negative_after_negative_ttl() {
+        let mut cache = FakeCache::new(2);
+        assert!(!cache.results(0));
+        assert!(!cache.results(3));
+        assert!(cache.results(10));
+        assert!(cache.results(11));
+    }
+
+    #[test]
+    fn botd_is_called_once_per_negative_ttl() {
+        let mut cache = FakeCache::new(2);
+        for now in 0..60 {
+            cache.results(now);
+        }
+        // the first lookup, then one fresh lookup per negative bucket
+        assert!(cache.botd_calls <= 1 + 60 / NEGATIVE_TTL + 1);
+    }
+
+    #[test]
+    fn zero_negative_ttl_still_caches_fresh_lookups() {
+        assert_eq!(negative_bucket_ttl(0), 1);
+        assert_eq!(bucket(10, 1), 10);
+        assert_eq!(bucket(14, 5), bucket(10, 5));
+    }
+
+    #[test]
+    fn processed_result_is_never_stale() {
+        assert!(!is_stale(false, 1000, 0));
+        assert!(is_stale(true, 6, 5));
+        assert!(!is_stale(true, 5, 5));
+    }
+}
@@ -0,0 +1,183 @@
+use fastly::{Request, Response};
+use fastly::http::Url;
+use sha2::{Digest, Sha256, Sha384};
+use fastly::http::header::{CACHE_CONTROL, COOKIE, LOCATION, SET_COOKIE};
+#[cfg(feature = "embedded-agent")]
+use fastly::http::header::CONTENT_TYPE;
+use crate::config::{Config, CDN_BACKEND_NAME};
+use crate::error::BotdError;
+use crate::utils::secure_eq;
+use BotdError::{CDNRedirectError, IntegrityMismatch, SendError};
+
+/// All agent files are under this path on the CDN.
+const AGENT_PATH_PREFIX: &str = "/botd/";
+const MAX_REDIRECTS: usize = 5;
+const AGENT_FILES: [&str; 4] = ["esm.js", "esm.min.js", "umd.js", "umd.min.js"];
+
+/// Pinned ESM bundle of the agent, see "Embedded agent" in README.
+#[cfg(feature = "embedded-agent")]
+const EMBEDDED_AGENT: &[u8] = include_bytes!("../agent/esm.min.js");
+#[cfg(feature = "embedded-agent")]
+const EMBEDDED_AGENT_VERSION: &str = include_str!("../agent/version");
+
+fn is_version(segment: &str) -> bool {
+    let bytes = segment.as_bytes();
+    bytes.len() > 1
+        && bytes[0] == b'v'
+        && bytes[1].is_ascii_digit()
+        && bytes.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'.' || *b == b'-')
+}
+
+/// Maps the path under the dist prefix (`/v{version}/{file}`) to the CDN path.
+/// Returns `None` for anything but agent files, so that other CDN paths aren't reachable through the site.
+pub fn agent_path(dist_path: &str) -> Option<String> {
+    let segments: Vec<&str> = dist_path.trim_start_matches('/').split('/').collect();
+    match segments.as_slice() {
+        [version, file] if is_version(version) && AGENT_FILES.contains(file) => {
+            Some(format!("{}{}/{}", AGENT_PATH_PREFIX, version, file))
+        },
+        _ => None
+    }
+}
+
+/// Path of the redirect target, redirects are only followed within agent files.
+fn redirect_path(location: &str) -> Option<String> {
+    let path = if location.starts_with('/') {
+        String::from(location)
+    } else {
+        String::from(Url::parse(location).ok()?.path())
+    };
+    if path.starts_with(AGENT_PATH_PREFIX) { Some(path) } else { None }
+}
+
+/// Fetches the agent file from the CDN, following redirects up to `MAX_REDIRECTS`.
+/// Responses are cached at the edge for `cdn_cache_ttl` seconds.
+pub fn fetch_agent(req: &Request, path: String, config: &Config) -> Result<Response, BotdError> {
+    let mut visited: Vec<String> = Vec::new();
+    let mut path = path;
+    loop {
+        if visited.contains(&path) {
+            log::error!("[cdn] Redirect loop at {}", path);
+            return Err(CDNRedirectError);
+        }
+        if visited.len() > MAX_REDIRECTS {
+            log::error!("[cdn] More than {} redirects", MAX_REDIRECTS);
+            return Err(CDNRedirectError);
+        }
+        log::debug!("[cdn] Fetch agent file {}", path);
+        let mut cdn_req = req.clone_without_body().with_path(&path);
+        cdn_req.remove_query();
+        cdn_req.remove_header(COOKIE);
+        cdn_req.set_pass(false);
+        cdn_req.set_ttl(config.cdn_cache_ttl);
+        let resp = cdn_req.send(CDN_BACKEND_NAME).map_err(|e| SendError(Box::new(e)))?;
+        visited.push(path);
+        if !resp.get_status().is_redirection() {
+            return Ok(cacheable(resp, config));
+        }
+        path = match resp.get_header_str(LOCATION).and_then(redirect_path) {
+            Some(p) => p,
+            _ => {
+                log::error!("[cdn] Redirect without valid location");
+                return Err(CDNRedirectError);
+            }
+        };
+    }
+}
+
+/// Version and file of the agent from the CDN path, e.g. `0.1` and `esm.min.js` for `/botd/v0.1/esm.min.js`.
+fn path_version_file(path: &str) -> Option<(&str, &str)> {
+    let mut parts = path.strip_prefix(AGENT_PATH_PREFIX)?.strip_prefix('v')?.splitn(2, '/');
+    Some((parts.next()?, parts.next()?))
+}
+
+fn matches_digest(body: &[u8], expected: &str) -> bool {
+    // `integrity` attribute format: space-separated `{algorithm}-{base64 digest}`, any of them may match
+    expected.split_whitespace().any(|d| {
+        let actual = match d.splitn(2, '-').next() {
+            Some("sha256") => format!("sha256-{}", base64::encode(Sha256::digest(body))),
+            Some("sha384") => format!("sha384-{}", base64::encode(Sha384::digest(body))),
+            _ => {
+                log::error!("[cdn] Unsupported integrity digest {}", d);
+                return false;
+            }
+        };
+        secure_eq(&actual, d)
+    })
+}
+
+/// Checks the agent response against the digest configured for its version and file.
+/// Responses without configured digest and error responses are passed as is.
+pub fn verify_integrity(mut resp: Response, path: &str, config: &Config) -> Result<Response, BotdError> {
+    let expected = match path_version_file(path).and_then(|(v, f)| config.agent_integrity(v, f)) {
+        Some(e) => e,
+        _ => return Ok(resp)
+    };
+    if !resp.get_status().is_success() {
+        return Ok(resp);
+    }
+    let body = resp.take_body_bytes();
+    if !matches_digest(&body, expected) {
+        log::error!("[cdn] Agent {} doesn't match configured digest", path);
+        return Err(IntegrityMismatch(String::from(path)));
+    }
+    log::debug!("[cdn] Agent {} integrity verified", path);
+    Ok(resp.with_body(body))
+}
+
+fn cacheable(mut resp: Response, config: &Config) -> Response {
+    resp.remove_header(SET_COOKIE);
+    if resp.get_status().is_success() {
+        resp.set_header(CACHE_CONTROL, format!("public, max-age={}", config.cdn_cache_ttl));
+    }
+    resp
+}
+
+/// Embedded copy of the agent, only for the exact version and file it is.
+/// `None` if the build doesn't include it or another agent file is requested.
+#[cfg(feature = "embedded-agent")]
+pub fn embedded_agent(path: &str, config: &Config) -> Option<Response> {
+    if path_version_file(path)? != (EMBEDDED_AGENT_VERSION.trim(), "esm.min.js") {
+        log::debug!("[cdn] Requested {}, embedded agent is v{}/esm.min.js", path, EMBEDDED_AGENT_VERSION.trim());
+        return None;
+    }
+    Some(Response::from_body(EMBEDDED_AGENT)
+        .with_header(CONTENT_TYPE, "application/javascript; charset=utf-8")
+        .with_header(CACHE_CONTROL, format!("public, max-age={}", config.cdn_cache_ttl)))
+}
+
+#[cfg(not(feature = "embedded-agent"))]
+pub fn embedded_agent(_path: &str, _config: &Config) -> Option<Response> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agent_files_are_mapped_to_cdn_paths() {
+        assert_eq!(agent_path("/v0.1/esm.min.js"), Some(String::from("/botd/v0.1/esm.min.js")));
+        assert_eq!(agent_path("/v1.0.0-beta/umd.js"), Some(String::from("/botd/v1.0.0-beta/umd.js")));
+        assert_eq!(agent_path("v0.1/esm.js"), Some(String::from("/botd/v0.1/esm.js")));
+    }
+
+    #[test]
+    fn other_cdn_paths_are_rejected() {
+        let paths = [
+            "/v0.1/other.js", "/v0.1/esm.min.js.map", "/0.1/esm.min.js", "/v/esm.min.js", "/va/esm.min.js",
+            "/v0.1/../../other/esm.min.js", "/../v0.1/esm.min.js", "/v0.1/sub/esm.min.js", "/v0%2F1/esm.js",
+            "/v0.1", "/", ""
+        ];
+        for path in paths.iter() {
+            assert_eq!(agent_path(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn version_and_file_are_read_from_cdn_path() {
+        assert_eq!(path_version_file("/botd/v0.1/esm.min.js"), Some(("0.1", "esm.min.js")));
+        assert_eq!(path_version_file("/other/v0.1/esm.min.js"), None);
+        assert_eq!(path_version_file("/botd/v0.1"), None);
+    }
+}
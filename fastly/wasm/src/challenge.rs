@@ -0,0 +1,65 @@
+use fastly::{Request, Response};
+use fastly::http::header::{CACHE_CONTROL, CONTENT_TYPE, SET_COOKIE};
+use crate::{PROCESSED_STATUS, REQUEST_STATUS_HEADER};
+use crate::assets::is_navigation_requested;
+use crate::config::Config;
+use crate::error::BotdError;
+use crate::injector::inject_challenge;
+use crate::request_id::get_cookie;
+use crate::utils::{get_e_tld_plus_one, make_cookie};
+
+/// Loop guard, set with the challenge page, so that a client which can't pass
+/// the challenge (e.g. with JavaScript disabled) isn't challenged again and again.
+const CHALLENGE_COOKIE: &str = "botd-challenge";
+const AUTOMATION_TOOL_STATUS_HEADER: &str = "botd-automation-tool-status";
+
+const CHALLENGE_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head>
+    <meta charset=\"utf-8\">
+    <meta name=\"robots\" content=\"noindex\">
+    <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+    <title>Checking your browser</title>
+</head>
+<body>
+    <p>Checking your browser, the page will reload automatically.</p>
+    <noscript><p>Please enable JavaScript to continue.</p></noscript>
+    <script>
+        document.addEventListener('botd:result', function () { location.reload() })
+    </script>
+</body>
+</html>";
+
+fn is_challenged(req: &Request, config: &Config) -> bool {
+    get_cookie(req, &config.cookie.full_name(CHALLENGE_COOKIE)).is_some()
+}
+
+/// Only page loads can be challenged, reloading repeats the request as is.
+/// Other requests (e.g. `fetch` of JSON) would get HTML instead of their data, they are processed like `results`.
+pub fn can_challenge(req: &Request, config: &Config) -> bool {
+    if !is_navigation_requested(req) {
+        return false;
+    }
+    if is_challenged(req, config) {
+        log::info!("[challenge] Client has already been challenged");
+        return false;
+    }
+    true
+}
+
+/// Whether the results transferred to the request headers need another detection run.
+pub fn is_suspicious(req: &Request) -> bool {
+    req.get_header_str(REQUEST_STATUS_HEADER) != Some(PROCESSED_STATUS)
+        || req.get_header_str(AUTOMATION_TOOL_STATUS_HEADER) == Some("bad")
+}
+
+/// Page which runs detection through the detect endpoint, which sets the request id cookie,
+/// and then reloads the original URL.
+pub fn challenge_response(req: &Request, config: &Config) -> Result<Response, BotdError> {
+    let page = inject_challenge(CHALLENGE_PAGE, config)?;
+    let guard = make_cookie(CHALLENGE_COOKIE, String::from("1"), get_e_tld_plus_one(req), Some(config.challenge_guard_ttl), &config.cookie);
+    Ok(Response::from_body(page)
+        .with_header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .with_header(CACHE_CONTROL, "no-store")
+        .with_header(SET_COOKIE, guard))
+}
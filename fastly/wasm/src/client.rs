@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use fastly::Request;
+use fastly::geo::geo_lookup;
+use json::JsonValue;
+
+/// Connection metadata that is only available on the original client request,
+/// it's lost when the request is cloned.
+pub struct ClientInfo {
+    pub tls_protocol: Option<String>,
+    pub tls_cipher: Option<String>,
+    pub tls_ja3_md5: Option<String>,
+    pub http_version: String,
+    /// Headers in the order they were sent, with original name case and every value of repeated headers.
+    pub headers: Vec<(String, String)>
+}
+
+fn ordered_headers(req: &Request) -> Vec<(String, String)> {
+    let names = match req.get_original_header_names() {
+        Some(n) => n,
+        _ => {
+            log::debug!("[client] Original header names are not available");
+            return req.get_headers()
+                .filter_map(|(n, v)| Some((n.to_string(), String::from(v.to_str().ok()?))))
+                .collect();
+        }
+    };
+    // the same name is listed once per occurrence, so take the next value of the header each time
+    let mut occurrences: HashMap<String, usize> = HashMap::new();
+    let mut headers = Vec::new();
+    for name in names {
+        let index = occurrences.entry(name.to_lowercase()).or_insert(0);
+        if let Some(value) = req.get_header_all(name.as_str()).nth(*index) {
+            if let Ok(v) = value.to_str() {
+                headers.push((name.clone(), String::from(v)));
+            }
+        }
+        *index += 1;
+    }
+    headers
+}
+
+impl ClientInfo {
+    pub fn from_req(req: &Request) -> Self {
+        let tls_ja3_md5 = req.get_tls_ja3_md5()
+            .map(|h| h.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+        ClientInfo {
+            tls_protocol: req.get_tls_protocol().map(String::from),
+            tls_cipher: req.get_tls_cipher_openssl_name().map(String::from),
+            tls_ja3_md5,
+            http_version: format!("{:?}", req.get_version()),
+            headers: ordered_headers(req)
+        }
+    }
+
+    pub fn tls_json(&self) -> JsonValue {
+        let mut json = JsonValue::new_object();
+        json["protocol"] = self.tls_protocol.to_owned().into();
+        json["cipher"] = self.tls_cipher.to_owned().into();
+        json["ja3_md5"] = self.tls_ja3_md5.to_owned().into();
+        json
+    }
+}
+
+pub fn geo_json(ip: &str) -> JsonValue {
+    let geo = match IpAddr::from_str(ip).ok().and_then(geo_lookup) {
+        Some(g) => g,
+        _ => {
+            log::debug!("[geo] No geolocation data for client ip");
+            return JsonValue::Null;
+        }
+    };
+    let mut json = JsonValue::new_object();
+    json["as_number"] = geo.as_number().into();
+    json["as_name"] = geo.as_name().into();
+    json["country_code"] = geo.country_code().into();
+    json["city"] = geo.city().into();
+    json["proxy_type"] = format!("{:?}", geo.proxy_type()).into();
+    json["proxy_description"] = format!("{:?}", geo.proxy_description()).into();
+    json
+}
@@ -1,8 +1,18 @@
 use crate::error::BotdError;
 use fastly::{Dictionary, Request};
-use BotdError::{Disabled, NoTokenInConfig};
+use BotdError::{Disabled, NoHashIpSalt, NoTokenInConfig};
 use log::LevelFilter::{Debug, Info};
 use crate::utils::get_ip;
+use crate::client::ClientInfo;
+use crate::backend::Budget;
+use crate::protocol::{ApiVersion, Protocol};
+use crate::REQUEST_ID_HEADER_COOKIE;
+use crate::request_id::RequestIdSource;
+use cookie::SameSite;
+use crate::assets::AssetConfig;
+use crate::routes::RouteTable;
+use crate::injector::{parse_load_options, AgentTrigger, Placement, Strategy};
+use serde_json::{Map, Value};
 
 /// This should match the name of your storage backend. See the the `Hosts` section of
 /// the Fastly WASM service UI for more information.
@@ -10,23 +20,252 @@ pub const APP_BACKEND_NAME: &str = "backend";
 pub const BOTD_BACKEND_NAME: &str = "botd";
 pub const CDN_BACKEND_NAME: &str = "cdn";
 
+const CONFIG_DICT_NAME: &str = "botd_config";
+const CONFIG_TOKEN: &str = "token";
+const CONFIG_AGENT_VERSION: &str = "agent_version";
+const CONFIG_HEALTH_SECRET: &str = "health_secret";
+const DEFAULT_AGENT_VERSION: &str = "0.1";
+
+/// Settings of the health endpoint. They are read apart from `Config`, so that the endpoint
+/// keeps working and reports the errors which make `Config::new` fail.
+pub struct HealthConfig {
+    pub secret: Option<String>,
+    pub token: Option<String>,
+    pub agent_version: String
+}
+
+impl HealthConfig {
+    pub fn read() -> Self {
+        let dictionary = Dictionary::open(CONFIG_DICT_NAME);
+        let agent_version_default = || String::from(DEFAULT_AGENT_VERSION);
+        HealthConfig {
+            secret: dictionary.get(CONFIG_HEALTH_SECRET).filter(|s| !s.is_empty()),
+            token: dictionary.get(CONFIG_TOKEN),
+            agent_version: dictionary.get(CONFIG_AGENT_VERSION).unwrap_or_else(agent_version_default)
+        }
+    }
+}
+
 pub struct Config {
     pub token: String,
     pub ip: String,
+    pub client: ClientInfo,
     pub agent_version: String,
-    pub debug: bool
+    pub debug: bool,
+    pub debug_secret: Option<String>,
+    pub edge_headers_allow: Option<Vec<String>>,
+    pub edge_headers_deny: Vec<String>,
+    pub hash_ip: bool,
+    /// Secret salt of IP hashes, required with `hash_ip`.
+    pub hash_ip_salt: String,
+    pub edge_tls: bool,
+    pub edge_http: bool,
+    pub edge_geo: bool,
+    pub botd_first_byte_timeout_ms: u64,
+    pub botd_total_timeout_ms: u64,
+    pub botd_retries: u32,
+    pub budget: Budget,
+    pub fail_closed: bool,
+    pub tag_only: bool,
+    pub results_cache_ttl: u32,
+    pub results_cache_negative_ttl: u32,
+    pub verdict_cookie_key: Option<String>,
+    pub verdict_cookie_ttl: u64,
+    pub protocol: Protocol,
+    pub api_version: ApiVersion,
+    pub detect_allowed_origins: Vec<String>,
+    pub detect_max_body_bytes: u64,
+    pub cookie: CookieConfig,
+    pub assets: AssetConfig,
+    pub routes: RouteTable,
+    pub inject_on_navigation: bool,
+    /// Methods which skip bot detection.
+    pub bypass_methods: Vec<String>,
+    /// Methods which are blocked when bot detection fails, regardless of `fail_mode`.
+    pub strict_methods: Vec<String>,
+    pub cdn_cache_ttl: u32,
+    /// Serve the embedded agent instead of proxying the CDN.
+    pub agent_self_host: bool,
+    /// Expected SRI digests (`sha256-...` or `sha384-...`) of agent files by `{version}/{file}`.
+    pub agent_integrity: Vec<(String, String)>,
+    /// Extra options of `Botd.load` in the injected script.
+    pub agent_load_options: Option<Map<String, Value>>,
+    pub agent_trigger: AgentTrigger,
+    pub agent_placement: Placement,
+    pub agent_strategy: Strategy,
+    /// Sources of the request id for results lookup, in order of precedence.
+    pub request_id_sources: Vec<RequestIdSource>,
+    pub request_id_header: String,
+    pub request_id_query: Option<String>,
+    /// Lifetime of the challenge loop guard cookie in seconds.
+    pub challenge_guard_ttl: u64
+}
+
+/// Attributes of cookies set by the integration.
+pub struct CookieConfig {
+    /// Name of the request id cookie, without prefix.
+    pub name: String,
+    /// Lifetime of the request id cookie in seconds, session cookie if `None`.
+    pub max_age: Option<u64>,
+    pub same_site: SameSite,
+    /// Cookies get `__Host-` prefix, which implies host-only, `Secure` and `Path=/`.
+    pub host_prefix: bool,
+    pub host_only: bool,
+    /// Overrides default eTLD+1 cookie domain.
+    pub domain: Option<String>,
+    /// Adds `Partitioned` attribute (CHIPS).
+    pub partitioned: bool,
+    pub secure: bool,
+    /// Refresh the request id cookie on non-static responses.
+    pub sliding: bool
+}
+
+impl CookieConfig {
+    fn new(dictionary: &Dictionary) -> Self {
+        const CONFIG_COOKIE_NAME: &str = "cookie_name";
+        const CONFIG_COOKIE_MAX_AGE: &str = "cookie_max_age";
+        const CONFIG_COOKIE_SAME_SITE: &str = "cookie_same_site";
+        const CONFIG_COOKIE_HOST_PREFIX: &str = "cookie_host_prefix";
+        const CONFIG_COOKIE_HOST_ONLY: &str = "cookie_host_only";
+        const CONFIG_COOKIE_DOMAIN: &str = "cookie_domain";
+        const CONFIG_COOKIE_PARTITIONED: &str = "cookie_partitioned";
+        const CONFIG_COOKIE_INSECURE: &str = "cookie_insecure";
+        const CONFIG_COOKIE_SLIDING: &str = "cookie_sliding";
+
+        let is_enabled = |key: &str| dictionary.get(key).map_or(false, |v| v == true.to_string());
+
+        let name_default = || String::from(REQUEST_ID_HEADER_COOKIE);
+        let name = dictionary.get(CONFIG_COOKIE_NAME).filter(|n| !n.is_empty()).unwrap_or_else(name_default);
+        let max_age = dictionary.get(CONFIG_COOKIE_MAX_AGE).map(|a| parse_number(Some(a), 0)).filter(|a| *a > 0);
+        let host_prefix = is_enabled(CONFIG_COOKIE_HOST_PREFIX);
+        // `__Host-` prefixed cookies are rejected by browsers without `Secure`
+        let secure = host_prefix || !is_enabled(CONFIG_COOKIE_INSECURE);
+        let mut same_site = match dictionary.get(CONFIG_COOKIE_SAME_SITE).unwrap_or_default().to_lowercase().as_str() {
+            "lax" => SameSite::Lax,
+            "strict" => SameSite::Strict,
+            _ => SameSite::None
+        };
+        if !secure && same_site == SameSite::None {
+            log::error!("[config] SameSite=None requires Secure cookie, using SameSite=Lax");
+            same_site = SameSite::Lax;
+        }
+
+        CookieConfig {
+            name,
+            max_age,
+            same_site,
+            host_prefix,
+            host_only: is_enabled(CONFIG_COOKIE_HOST_ONLY),
+            domain: dictionary.get(CONFIG_COOKIE_DOMAIN).filter(|d| !d.is_empty()),
+            partitioned: is_enabled(CONFIG_COOKIE_PARTITIONED),
+            secure,
+            sliding: is_enabled(CONFIG_COOKIE_SLIDING)
+        }
+    }
+
+    /// Name of the cookie as it's sent to the client.
+    pub fn full_name(&self, name: &str) -> String {
+        if self.host_prefix { format!("__Host-{}", name) } else { String::from(name) }
+    }
+
+    pub fn request_id_name(&self) -> String {
+        self.full_name(&self.name)
+    }
+}
+
+fn parse_number(value: Option<String>, default: u64) -> u64 {
+    match value {
+        Some(v) => v.trim().parse().unwrap_or_else(|_| {
+            log::error!("[config] Can't parse number {}, using default {}", v, default);
+            default
+        }),
+        _ => default
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|v| v.trim().to_lowercase())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Parses `key=value` pairs separated by commas, values keep their case.
+fn parse_map(value: &str) -> Vec<(String, String)> {
+    value.split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next()?.trim();
+            let value = parts.next()?.trim();
+            if key.is_empty() || value.is_empty() {
+                log::error!("[config] Can't parse pair {}, ignored", pair);
+                return None;
+            }
+            Some((String::from(key), String::from(value)))
+        })
+        .collect()
 }
 
 impl Config {
     pub fn new(req: &Request) -> Result<Self, BotdError> {
         const DEFAULT_LOG_ENDPOINT: &str = "default";
-        const DEFAULT_AGENT_VERSION: &str = "0.1";
-        const CONFIG_DICT_NAME: &str = "botd_config";
-        const CONFIG_TOKEN: &str = "token";
+        const DEFAULT_BOTD_FIRST_BYTE_TIMEOUT_MS: u64 = 1000;
+        const DEFAULT_BOTD_TOTAL_TIMEOUT_MS: u64 = 2000;
+        const DEFAULT_BOTD_RETRIES: u64 = 1;
+        const MAX_BOTD_RETRIES: u64 = 5;
+        const DEFAULT_DETECTION_BUDGET_MS: u64 = 3000;
+        const DEFAULT_PROTOCOL: &str = "header";
+        const DEFAULT_API_VERSION: &str = "v1";
+        const DEFAULT_DETECT_MAX_BODY_BYTES: u64 = 64 * 1024;
+        const DEFAULT_VERDICT_COOKIE_TTL: u64 = 300;
+        const DEFAULT_CDN_CACHE_TTL: u64 = 3600;
+        const DEFAULT_CHALLENGE_GUARD_TTL: u64 = 30;
+        const DEFAULT_REQUEST_ID_SOURCES: &str = "cookie,header";
+        const DEFAULT_BYPASS_METHODS: &str = "options,head";
+        const ALWAYS_EDGE_HEADERS_DENY: &str = "cookie,authorization,proxy-authorization,x-api-key";
         const CONFIG_LOG_ENDPOINT: &str = "log_endpoint";
         const CONFIG_DISABLE: &str = "disable";
         const CONFIG_DEBUG: &str = "debug";
-        const CONFIG_AGENT_VERSION: &str = "agent_version";
+        const CONFIG_DEBUG_SECRET: &str = "debug_secret";
+        const CONFIG_EDGE_HEADERS_ALLOW: &str = "edge_headers_allow";
+        const CONFIG_EDGE_HEADERS_DENY: &str = "edge_headers_deny";
+        const CONFIG_HASH_IP: &str = "hash_ip";
+        const CONFIG_HASH_IP_SALT: &str = "hash_ip_salt";
+        const CONFIG_EDGE_TLS: &str = "edge_tls";
+        const CONFIG_EDGE_HTTP: &str = "edge_http";
+        const CONFIG_EDGE_GEO: &str = "edge_geo";
+        const CONFIG_BOTD_FIRST_BYTE_TIMEOUT: &str = "botd_first_byte_timeout_ms";
+        const CONFIG_BOTD_TOTAL_TIMEOUT: &str = "botd_total_timeout_ms";
+        const CONFIG_BOTD_RETRIES: &str = "botd_retries";
+        const CONFIG_DETECTION_BUDGET: &str = "detection_budget_ms";
+        const CONFIG_FAIL_MODE: &str = "fail_mode";
+        const CONFIG_DETECTION_MODE: &str = "detection_mode";
+        const CONFIG_RESULTS_CACHE_TTL: &str = "results_cache_ttl";
+        const CONFIG_RESULTS_CACHE_NEGATIVE_TTL: &str = "results_cache_negative_ttl";
+        const CONFIG_VERDICT_COOKIE_KEY: &str = "verdict_cookie_key";
+        const CONFIG_VERDICT_COOKIE_TTL: &str = "verdict_cookie_ttl";
+        const CONFIG_PROTOCOL: &str = "protocol";
+        const CONFIG_API_VERSION: &str = "api_version";
+        const CONFIG_DETECT_ALLOWED_ORIGINS: &str = "detect_allowed_origins";
+        const CONFIG_DETECT_MAX_BODY_BYTES: &str = "detect_max_body_bytes";
+        const CONFIG_STATIC_EXTENSIONS: &str = "static_extensions";
+        const CONFIG_STATIC_PATH_PREFIXES: &str = "static_path_prefixes";
+        const CONFIG_STATIC_SKIP_CLASSES: &str = "static_skip_classes";
+        const CONFIG_ROUTES: &str = "routes";
+        const CONFIG_INJECT_ON_NAVIGATION: &str = "inject_on_navigation";
+        const CONFIG_BYPASS_METHODS: &str = "bypass_methods";
+        const CONFIG_STRICT_METHODS: &str = "strict_methods";
+        const CONFIG_CDN_CACHE_TTL: &str = "cdn_cache_ttl";
+        const CONFIG_AGENT_SOURCE: &str = "agent_source";
+        const CONFIG_AGENT_INTEGRITY: &str = "agent_integrity";
+        const CONFIG_AGENT_LOAD_OPTIONS: &str = "agent_load_options";
+        const CONFIG_AGENT_TRIGGER: &str = "agent_trigger";
+        const CONFIG_AGENT_PLACEMENT: &str = "agent_placement";
+        const CONFIG_AGENT_STRATEGY: &str = "agent_strategy";
+        const CONFIG_REQUEST_ID_SOURCES: &str = "request_id_sources";
+        const CONFIG_REQUEST_ID_HEADER: &str = "request_id_header";
+        const CONFIG_REQUEST_ID_QUERY: &str = "request_id_query";
+        const CONFIG_CHALLENGE_GUARD_TTL: &str = "challenge_guard_ttl";
 
         let dictionary = Dictionary::open(CONFIG_DICT_NAME);
 
@@ -43,6 +282,7 @@ impl Config {
         }
 
         let ip = get_ip(req);
+        let client = ClientInfo::from_req(req);
 
         if let Some(d) = dictionary.get(CONFIG_DISABLE) {
             if d == true.to_string() { return Err(Disabled); }
@@ -55,6 +295,157 @@ impl Config {
         let agent_version_default = || String::from(DEFAULT_AGENT_VERSION);
         let agent_version = dictionary.get(CONFIG_AGENT_VERSION).unwrap_or_else(agent_version_default);
 
-        Ok(Config { token, ip, agent_version, debug })
+        let debug_secret = dictionary.get(CONFIG_DEBUG_SECRET).filter(|s| !s.is_empty());
+
+        let edge_headers_allow = dictionary.get(CONFIG_EDGE_HEADERS_ALLOW).map(|a| parse_list(&a));
+
+        // configured headers are added to the default ones, sensitive headers are never sent to botd
+        let mut edge_headers_deny = parse_list(ALWAYS_EDGE_HEADERS_DENY);
+        edge_headers_deny.extend(dictionary.get(CONFIG_EDGE_HEADERS_DENY).map(|d| parse_list(&d)).unwrap_or_default());
+
+        let hash_ip_default = || false.to_string();
+        let hash_ip = dictionary.get(CONFIG_HASH_IP).unwrap_or_else(hash_ip_default) == true.to_string();
+        // the token is public, so it can't be the salt: hashes of all IPv4 addresses could be precomputed
+        let hash_ip_salt = dictionary.get(CONFIG_HASH_IP_SALT).filter(|s| !s.is_empty()).unwrap_or_default();
+        if hash_ip && hash_ip_salt.is_empty() {
+            return Err(NoHashIpSalt);
+        }
+
+        let edge_signal_default = || false.to_string();
+        let edge_tls = dictionary.get(CONFIG_EDGE_TLS).unwrap_or_else(edge_signal_default) == true.to_string();
+        let edge_http = dictionary.get(CONFIG_EDGE_HTTP).unwrap_or_else(edge_signal_default) == true.to_string();
+        let edge_geo = dictionary.get(CONFIG_EDGE_GEO).unwrap_or_else(edge_signal_default) == true.to_string();
+
+        let botd_first_byte_timeout_ms = parse_number(dictionary.get(CONFIG_BOTD_FIRST_BYTE_TIMEOUT), DEFAULT_BOTD_FIRST_BYTE_TIMEOUT_MS);
+        let botd_total_timeout_ms = parse_number(dictionary.get(CONFIG_BOTD_TOTAL_TIMEOUT), DEFAULT_BOTD_TOTAL_TIMEOUT_MS);
+        let botd_retries = parse_number(dictionary.get(CONFIG_BOTD_RETRIES), DEFAULT_BOTD_RETRIES).min(MAX_BOTD_RETRIES) as u32;
+        let budget = Budget::new(parse_number(dictionary.get(CONFIG_DETECTION_BUDGET), DEFAULT_DETECTION_BUDGET_MS));
+
+        let fail_mode_default = || String::from("open");
+        let fail_closed = dictionary.get(CONFIG_FAIL_MODE).unwrap_or_else(fail_mode_default) == "closed";
+
+        let detection_mode_default = || String::from("enforce");
+        let tag_only = dictionary.get(CONFIG_DETECTION_MODE).unwrap_or_else(detection_mode_default) == "tag";
+
+        let results_cache_ttl = parse_number(dictionary.get(CONFIG_RESULTS_CACHE_TTL), 0) as u32;
+        let results_cache_negative_ttl = parse_number(dictionary.get(CONFIG_RESULTS_CACHE_NEGATIVE_TTL), 0) as u32;
+
+        let verdict_cookie_key = dictionary.get(CONFIG_VERDICT_COOKIE_KEY).filter(|k| !k.is_empty());
+        let verdict_cookie_ttl = parse_number(dictionary.get(CONFIG_VERDICT_COOKIE_TTL), DEFAULT_VERDICT_COOKIE_TTL);
+
+        let protocol_default = || String::from(DEFAULT_PROTOCOL);
+        let protocol = Protocol::parse(&dictionary.get(CONFIG_PROTOCOL).unwrap_or_else(protocol_default));
+        let api_version_default = || String::from(DEFAULT_API_VERSION);
+        let api_version = ApiVersion::parse(&dictionary.get(CONFIG_API_VERSION).unwrap_or_else(api_version_default))?;
+
+        let detect_allowed_origins = dictionary.get(CONFIG_DETECT_ALLOWED_ORIGINS).map(|o| parse_list(&o)).unwrap_or_default();
+        let detect_max_body_bytes = parse_number(dictionary.get(CONFIG_DETECT_MAX_BODY_BYTES), DEFAULT_DETECT_MAX_BODY_BYTES);
+
+        let cookie = CookieConfig::new(&dictionary);
+
+        let assets = AssetConfig::new(
+            dictionary.get(CONFIG_STATIC_EXTENSIONS).map(|e| parse_list(&e)).unwrap_or_default(),
+            dictionary.get(CONFIG_STATIC_PATH_PREFIXES).map(|p| parse_list(&p)).unwrap_or_default(),
+            dictionary.get(CONFIG_STATIC_SKIP_CLASSES).map(|c| parse_list(&c))
+        );
+
+        let routes = RouteTable::parse(dictionary.get(CONFIG_ROUTES).filter(|r| !r.is_empty()))?;
+
+        let inject_on_navigation_default = || false.to_string();
+        let inject_on_navigation = dictionary.get(CONFIG_INJECT_ON_NAVIGATION).unwrap_or_else(inject_on_navigation_default) == true.to_string();
+
+        let bypass_methods_default = || String::from(DEFAULT_BYPASS_METHODS);
+        let bypass_methods = parse_list(&dictionary.get(CONFIG_BYPASS_METHODS).unwrap_or_else(bypass_methods_default));
+        let strict_methods = dictionary.get(CONFIG_STRICT_METHODS).map(|m| parse_list(&m)).unwrap_or_default();
+
+        let cdn_cache_ttl = parse_number(dictionary.get(CONFIG_CDN_CACHE_TTL), DEFAULT_CDN_CACHE_TTL) as u32;
+
+        let agent_source_default = || String::from("cdn");
+        let agent_self_host = dictionary.get(CONFIG_AGENT_SOURCE).unwrap_or_else(agent_source_default) == "embedded";
+
+        let agent_integrity = dictionary.get(CONFIG_AGENT_INTEGRITY).map(|i| parse_map(&i)).unwrap_or_default();
+
+        let agent_load_options = dictionary.get(CONFIG_AGENT_LOAD_OPTIONS).and_then(|o| parse_load_options(&o));
+        let agent_trigger_default = || String::from("load");
+        let agent_trigger = AgentTrigger::parse(&dictionary.get(CONFIG_AGENT_TRIGGER).unwrap_or_else(agent_trigger_default));
+        let agent_placement_default = || String::from("head-start");
+        let agent_placement = Placement::parse(&dictionary.get(CONFIG_AGENT_PLACEMENT).unwrap_or_else(agent_placement_default));
+        let agent_strategy_default = || String::from("inline");
+        let agent_strategy = Strategy::parse(&dictionary.get(CONFIG_AGENT_STRATEGY).unwrap_or_else(agent_strategy_default));
+
+        let request_id_sources_default = || String::from(DEFAULT_REQUEST_ID_SOURCES);
+        let request_id_sources = parse_list(&dictionary.get(CONFIG_REQUEST_ID_SOURCES).unwrap_or_else(request_id_sources_default))
+            .iter()
+            .filter_map(|s| RequestIdSource::parse(s))
+            .collect();
+        let request_id_header_default = || String::from(REQUEST_ID_HEADER_COOKIE);
+        let request_id_header = dictionary.get(CONFIG_REQUEST_ID_HEADER).filter(|h| !h.is_empty()).unwrap_or_else(request_id_header_default);
+        let request_id_query = dictionary.get(CONFIG_REQUEST_ID_QUERY).filter(|q| !q.is_empty());
+
+        let challenge_guard_ttl = parse_number(dictionary.get(CONFIG_CHALLENGE_GUARD_TTL), DEFAULT_CHALLENGE_GUARD_TTL);
+
+        Ok(Config {
+            token,
+            ip,
+            client,
+            agent_version,
+            debug,
+            debug_secret,
+            edge_headers_allow,
+            edge_headers_deny,
+            hash_ip,
+            hash_ip_salt,
+            edge_tls,
+            edge_http,
+            edge_geo,
+            botd_first_byte_timeout_ms,
+            botd_total_timeout_ms,
+            botd_retries,
+            budget,
+            fail_closed,
+            tag_only,
+            results_cache_ttl,
+            results_cache_negative_ttl,
+            verdict_cookie_key,
+            verdict_cookie_ttl,
+            protocol,
+            api_version,
+            detect_allowed_origins,
+            detect_max_body_bytes,
+            cookie,
+            assets,
+            routes,
+            inject_on_navigation,
+            bypass_methods,
+            strict_methods,
+            cdn_cache_ttl,
+            agent_self_host,
+            agent_integrity,
+            agent_load_options,
+            agent_trigger,
+            agent_placement,
+            agent_strategy,
+            request_id_sources,
+            request_id_header,
+            request_id_query,
+            challenge_guard_ttl
+        })
+    }
+
+    /// Expected digests of the agent file, space-separated as in `integrity` attribute.
+    pub fn agent_integrity(&self, version: &str, file: &str) -> Option<&str> {
+        let key = format!("{}/{}", version, file);
+        self.agent_integrity.iter().find(|(k, _)| *k == key).map(|(_, d)| d.as_str())
+    }
+
+    /// Strict methods are never bypassed, even if they are in both lists.
+    pub fn is_bypass_method(&self, req: &Request) -> bool {
+        let method = req.get_method_str().to_lowercase();
+        self.bypass_methods.contains(&method) && !self.strict_methods.contains(&method)
+    }
+
+    /// Whether failed bot detection blocks the request.
+    pub fn is_fail_closed(&self, req: &Request) -> bool {
+        self.fail_closed || self.strict_methods.contains(&req.get_method_str().to_lowercase())
     }
 }
\ No newline at end of file
@@ -1,35 +1,53 @@
 use fastly::{Request, Response};
 use BotdError::{NoRequestStatusInHeaders, NoErrorDescriptionInHeaders};
 use crate::config::Config;
-use crate::{ERROR_DESCRIPTION_HEADER, REQUEST_STATUS_HEADER};
+use crate::{ERROR_DESCRIPTION_HEADER, PROCESSED_STATUS, REQUEST_STATUS_HEADER};
 use crate::error::BotdError;
+use crate::backend::PendingBotdRequest;
+use crate::protocol::{BotdResults, RESULT_JSON_HEADER};
+
+/// Bot detection request which is sent to botd, but not finished yet.
+pub struct PendingDetect {
+    pub req_id: Option<String>,
+    pub request: PendingBotdRequest
+}
 
 pub trait Detect {
-    fn make(req: &mut Request, config: &Config) -> Result<Self, BotdError> where Self: Sized;
+    /// Sends detection request to botd without waiting for the result.
+    fn start(req: &Request, config: &Config) -> Result<PendingDetect, BotdError>;
+
+    /// Waits for the detection result and transfers it to the request headers.
+    fn wait(pending: PendingDetect, req: &mut Request, config: &Config) -> Result<Self, BotdError> where Self: Sized;
+
+    fn make(req: &mut Request, config: &Config) -> Result<Self, BotdError> where Self: Sized {
+        let pending = Self::start(req, config)?;
+        Self::wait(pending, req, config)
+    }
 }
 
-pub fn transfer_headers(req: &mut Request, botd_resp: &Response) {
-    static RESULT_HEADERS: [&str; 15] = [
-        "botd-request-id",
-        "botd-request-status",
-        "botd-error-description",
-        "botd-automation-tool-status",
-        "botd-automation-tool-prob",
-        "botd-automation-tool-type",
-        "botd-search-bot-status",
-        "botd-search-bot-prob",
-        "botd-search-bot-type",
-        "botd-browser-spoofing-status",
-        "botd-browser-spoofing-prob",
-        "botd-browser-spoofing-type",
-        "botd-vm-status",
-        "botd-vm-prob",
-        "botd-vm-type"
-    ];
-    for header_name in RESULT_HEADERS {
-        if let Some(header_value) = botd_resp.get_header(header_name) {
-            req.set_header(header_name, header_value);
-        };
+/// Headers with bot detection results, which are sent to the origin, stored in the verdict cookie and traced.
+pub static RESULT_HEADERS: [&str; 16] = [
+    "botd-request-id",
+    "botd-request-status",
+    "botd-error-description",
+    "botd-automation-tool-status",
+    "botd-automation-tool-prob",
+    "botd-automation-tool-type",
+    "botd-search-bot-status",
+    "botd-search-bot-prob",
+    "botd-search-bot-type",
+    "botd-browser-spoofing-status",
+    "botd-browser-spoofing-prob",
+    "botd-browser-spoofing-type",
+    "botd-vm-status",
+    "botd-vm-prob",
+    "botd-vm-type",
+    RESULT_JSON_HEADER
+];
+
+pub fn transfer_headers(req: &mut Request, results: &BotdResults) {
+    for (header_name, header_value) in results.headers.iter() {
+        req.set_header(*header_name, header_value.as_str());
     }
 }
 
@@ -39,7 +57,7 @@ pub fn check_botd_resp(resp: &Response) -> Result<(), BotdError> {
         Some(r) => r,
         _ => return Err(NoRequestStatusInHeaders)
     };
-    if !req_status.eq("processed") && resp.get_header(ERROR_DESCRIPTION_HEADER).is_none() {
+    if !req_status.eq(PROCESSED_STATUS) && resp.get_header(ERROR_DESCRIPTION_HEADER).is_none() {
         return Err(NoErrorDescriptionInHeaders);
     }
     Ok(())
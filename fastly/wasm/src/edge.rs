@@ -1,59 +1,84 @@
 use fastly::Request;
 use json::JsonValue;
 use crate::utils::get_timestamp_ms;
-use crate::config::{Config, BOTD_BACKEND_NAME};
-use crate::detector::{Detect, check_botd_resp, transfer_headers};
+use crate::config::Config;
+use crate::detector::{Detect, PendingDetect, transfer_headers};
 use crate::request_id::RequestId;
 use crate::error::BotdError;
+use BotdError::NoRequestIdInHeaders;
 use fastly::http::Method;
-use BotdError::SendError;
 use crate::CLIENT_IP_HEADER;
+use crate::redact::is_header_allowed;
+use crate::client::geo_json;
+use crate::backend::send_async_to_botd;
+use crate::protocol::read_results;
 
 pub struct EdgeDetect {
     pub req_id: String,
 }
 
 impl EdgeDetect {
-    fn create_body(req: &Request) -> String {
-        let prev_req_id = RequestId::from_req_cookie(req).unwrap_or_default();
+    fn create_body(req: &Request, config: &Config) -> String {
+        let prev_req_id = RequestId::from_req_cookie(req, &config.cookie.request_id_name()).unwrap_or_default();
         log::debug!("[edge] Previous request id: {}", prev_req_id);
-        let headers_names = req.get_header_names_str();
         let mut headers_json = JsonValue::new_object();
-        for header_name in headers_names {
-            let header_value = req.get_header_str(header_name);
-            if let Some(h) = header_value {
-                headers_json[header_name] = json::JsonValue::new_array();
-                if let Err(e) = headers_json[header_name].push(h) { log::error!("[error] {}", e.to_string()); }
+        let mut ordered_headers_json = JsonValue::new_array();
+        for (header_name, header_value) in config.client.headers.iter() {
+            if !is_header_allowed(header_name, config) {
+                continue;
             }
+            let key = header_name.to_lowercase();
+            if !headers_json.has_key(&key) {
+                headers_json[key.as_str()] = json::JsonValue::new_array();
+            }
+            if let Err(e) = headers_json[key.as_str()].push(header_value.as_str()) { log::error!("[error] {}", e.to_string()); }
+            let mut pair = JsonValue::new_object();
+            pair["name"] = header_name.as_str().into();
+            pair["value"] = header_value.as_str().into();
+            if let Err(e) = ordered_headers_json.push(pair) { log::error!("[error] {}", e.to_string()); }
         }
         let timestamp = get_timestamp_ms();
         let mut json = JsonValue::new_object();
         json["headers"] = headers_json;
+        json["ordered_headers"] = ordered_headers_json;
         json["path"] = req.get_path().into();
         json["previous_request_id"] = prev_req_id.into();
         json["timestamp"] = timestamp.into();
+        if config.edge_tls {
+            json["tls"] = config.client.tls_json();
+        }
+        if config.edge_http {
+            json["http_version"] = config.client.http_version.to_owned().into();
+        }
+        if config.edge_geo {
+            json["geo"] = geo_json(&config.ip);
+        }
         json.dump()
     }
 }
 
 impl Detect for EdgeDetect {
-    fn make(req: &mut Request, config: &Config) -> Result<Self, BotdError> {
-        let body = EdgeDetect::create_body(req);
-        let edge_resp = match req.clone_without_body()
+    fn start(req: &Request, config: &Config) -> Result<PendingDetect, BotdError> {
+        let body = EdgeDetect::create_body(req, config);
+        let edge_req = req.clone_without_body()
             .with_method(Method::POST)
-            .with_path("/api/v1/edge")
-            .with_query_str("header")
+            .with_path(&config.api_version.path("edge"))
+            .with_query_str(config.protocol.query())
             .with_body_text_plain(body.as_str())
             .with_header("Auth-Token", config.token.to_owned())
-            .with_header(CLIENT_IP_HEADER, config.ip.to_owned())
-            .send(BOTD_BACKEND_NAME) {
-            Ok(r) => r,
-            Err(e) => return Err(SendError(Box::new(e)))
-        };
-        check_botd_resp(&edge_resp)?;
-        let req_id = RequestId::from_resp_header(&edge_resp)?;
+            .with_header(CLIENT_IP_HEADER, config.ip.to_owned());
+        let request = send_async_to_botd(edge_req, false);
+        Ok(PendingDetect { req_id: None, request })
+    }
+
+    fn wait(pending: PendingDetect, req: &mut Request, config: &Config) -> Result<Self, BotdError> {
+        let edge_resp = pending.request.wait(config)?;
+        let results = read_results(edge_resp, config.protocol)?;
+        let req_id = results.req_id.to_owned()
+            .filter(|id| RequestId::is_valid(id))
+            .ok_or(NoRequestIdInHeaders)?;
         log::debug!("[edge] Edge detect request id: {}", req_id);
-        transfer_headers(req, &edge_resp);
+        transfer_headers(req, &results);
         Ok(EdgeDetect { req_id })
     }
 }
\ No newline at end of file
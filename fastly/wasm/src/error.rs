@@ -1,8 +1,11 @@
 use crate::{REQUEST_ID_HEADER_COOKIE, REQUEST_STATUS_HEADER, ERROR_DESCRIPTION_HEADER};
 use crate::config::{APP_BACKEND_NAME, Config};
-use crate::utils::{get_timestamp_ms, get_ip};
+use crate::utils::get_timestamp_ms;
 use crate::request_id::RequestId;
+use crate::trace::Trace;
+use crate::redact::{redact_ip, redact_token};
 use fastly::{Request, Response, Error};
+use fastly::http::StatusCode;
 use json::JsonValue;
 use fastly::http::request::SendError as FastlySendError;
 use std::panic::PanicInfo;
@@ -20,6 +23,8 @@ pub enum BotdError {
     NoTokenInConfig,
     /// Passed HTML string doesn't contain <head> tag
     Disabled,
+    /// `hash_ip` is enabled without `hash_ip_salt`.
+    NoHashIpSalt,
     /// Can't extract botd request id from headers.
     NoRequestIdInHeaders,
     /// Can't extract botd request status from headers.
@@ -32,8 +37,43 @@ pub enum BotdError {
     SendError(Box<FastlySendError>),
     /// Can't extract botd request status from headers.
     NoRequestIdInCookie,
+    /// Can't find request id in any of configured sources.
+    NoRequestId,
     /// Error connected with fp cdn.
     CDNRedirectError,
+    /// Botd didn't respond within the timeout, in milliseconds.
+    Timeout(u64),
+    /// Bot detection budget for the request is used up.
+    BudgetExhausted,
+    /// Can't read botd response body.
+    BodyRead(String),
+    /// Can't parse botd JSON response.
+    JsonParse(String),
+    /// Configured botd API version isn't supported.
+    UnsupportedApiVersion(String),
+    /// Can't parse route table from config.
+    InvalidRoutes(String),
+    /// Agent script from CDN doesn't match the configured digest.
+    IntegrityMismatch(String),
+}
+
+impl BotdError {
+    /// Errors of getting bot detection results, other errors (e.g. the agent injection)
+    /// don't mean the request wasn't checked, so they never block it.
+    pub fn is_detection_error(&self) -> bool {
+        matches!(self,
+            BotdError::NoRequestIdInHeaders
+            | BotdError::NoRequestStatusInHeaders
+            | BotdError::NoErrorDescriptionInHeaders
+            | BotdError::ToStringCast(_)
+            | BotdError::SendError(_)
+            | BotdError::NoRequestIdInCookie
+            | BotdError::NoRequestId
+            | BotdError::Timeout(_)
+            | BotdError::BudgetExhausted
+            | BotdError::BodyRead(_)
+            | BotdError::JsonParse(_))
+    }
 }
 
 impl ToString for BotdError {
@@ -43,13 +83,22 @@ impl ToString for BotdError {
             BotdError::WrongHTML => String::from("Can't find head tag in response body"),
             BotdError::NoTokenInConfig => String::from("Can't get botd token from config"),
             BotdError::Disabled => String::from("Bot detection disabled"),
+            BotdError::NoHashIpSalt => String::from("Can't get hash_ip_salt from config, it's required with hash_ip"),
             BotdError::NoRequestIdInHeaders => String::from("Request id cannot be found in headers"),
             BotdError::NoRequestStatusInHeaders => String::from("Request status cannot be found in headers"),
             BotdError::NoErrorDescriptionInHeaders => String::from("Request status is not processed, but error description cannot be found."),
             BotdError::ToStringCast(name) => format!("Can't cast {} to string", name),
             BotdError::SendError(e) => format!("Error occurred during sending to backend: {}", e.root_cause()),
             BotdError::NoRequestIdInCookie => String::from("Request id cannot be found in cookie"),
+            BotdError::NoRequestId => String::from("Request id cannot be found in request"),
             BotdError::CDNRedirectError => String::from("Error with Fp CDN"),
+            BotdError::Timeout(ms) => format!("Botd didn't respond within {} ms", ms),
+            BotdError::BudgetExhausted => String::from("Bot detection budget exhausted, detection skipped"),
+            BotdError::BodyRead(e) => format!("Can't read botd response body: {}", e),
+            BotdError::JsonParse(e) => format!("Can't parse botd response: {}", e),
+            BotdError::UnsupportedApiVersion(v) => format!("Botd API version {} is not supported", v),
+            BotdError::InvalidRoutes(e) => format!("Can't parse routes: {}", e),
+            BotdError::IntegrityMismatch(path) => format!("Integrity check failed for {}", path),
         }
     }
 }
@@ -63,22 +112,40 @@ fn send_error_to_app(req: Request, err: &BotdError, req_id: Option<String>) -> R
         .send(APP_BACKEND_NAME)?)
 }
 
+/// Reports the error to Rollbar and the debug trace without changing the request flow.
+/// Returns request id found in the request.
+pub fn report_error(req: &mut Request, err: &BotdError, config: Option<&Config>, trace: &mut Trace) -> Option<String> {
+    trace.error(err);
+    let cookie_name = config.map_or_else(|| String::from(REQUEST_ID_HEADER_COOKIE), |c| c.cookie.request_id_name());
+    let req_id = RequestId::search_in_req(req, &cookie_name);
+    // without config it's unknown whether the ip address has to be hashed, so it isn't reported
+    let (token, ip) = match config {
+        Some(c) => (redact_token(&c.token), redact_ip(&c.ip, c)),
+        _ => (String::new(), String::new())
+    };
+    send_error_to_rollbar(token, ip, req_id.to_owned(), err);
+    req_id
+}
+
 pub fn handle_error(
     mut req: Request,
     err: BotdError,
     config: Option<&Config>,
-    send_to_app: bool
+    send_to_app: bool,
+    trace: &mut Trace
 ) -> Result<Response, Error> {
     log::error!("[error] Handled error");
-    let req_id = RequestId::search_in_req(&mut req);
-    let (token, ip) = match config {
-        Some(c) => (c.token.to_owned(), c.ip.to_owned()),
-        _ => (String::new(), get_ip(&req))
-    };
-    send_error_to_rollbar(token, ip, req_id.to_owned(), &err);
+    let req_id = report_error(&mut req, &err, config, trace);
+    if send_to_app && err.is_detection_error() && config.map_or(false, |c| c.is_fail_closed(&req)) {
+        log::error!("[error] Fail closed, request is blocked");
+        trace.decision("block");
+        return Ok(Response::from_status(StatusCode::FORBIDDEN));
+    }
     if send_to_app {
+        trace.decision("forward-with-error");
         return send_error_to_app(req, &err, req_id);
     }
+    trace.decision("reject");
     let err_msg = format!("Error occurred during bot detection: {}", err.to_string());
     Err(Error::msg(err_msg))
 }
@@ -0,0 +1,123 @@
+use std::time::Instant;
+use fastly::{Backend, Request, Response};
+use fastly::http::{Method, StatusCode};
+use fastly::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use json::JsonValue;
+use regex::Regex;
+use crate::config::{Config, HealthConfig, APP_BACKEND_NAME, BOTD_BACKEND_NAME, CDN_BACKEND_NAME};
+use crate::error::BotdError;
+use crate::utils::secure_eq;
+
+pub const HEALTH_SECRET_HEADER: &str = "botd-health-secret";
+
+struct Probe {
+    defined: bool,
+    status: Option<u16>,
+    latency_ms: u64,
+    error: Option<String>
+}
+
+impl Probe {
+    fn run(req: &Request, backend: &str) -> Self {
+        let defined = Backend::from_name(backend).map(|b| b.exists()).unwrap_or(false);
+        if !defined {
+            return Probe { defined, status: None, latency_ms: 0, error: None };
+        }
+        // only the host of the health request, its path and query aren't sent to the backends
+        let mut url = req.get_url().clone();
+        url.set_path("/");
+        url.set_query(None);
+        url.set_fragment(None);
+        let mut probe_req = Request::new(Method::HEAD, url);
+        probe_req.set_pass(true);
+        let start = Instant::now();
+        let result = probe_req.send(backend);
+        let latency_ms = start.elapsed().as_millis() as u64;
+        match result {
+            Ok(r) => Probe { defined, status: Some(r.get_status().as_u16()), latency_ms, error: None },
+            Err(e) => Probe { defined, status: None, latency_ms, error: Some(e.root_cause().to_string()) }
+        }
+    }
+
+    /// Backend answered without a server error, other statuses (e.g. `404` for `/` of botd) are fine.
+    fn is_ok(&self) -> bool {
+        self.defined && self.error.is_none() && self.status.map_or(false, |s| s < 500)
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut json = JsonValue::new_object();
+        json["defined"] = self.defined.into();
+        json["reachable"] = self.is_ok().into();
+        json["status"] = self.status.into();
+        json["latency_ms"] = self.latency_ms.into();
+        json["error"] = self.error.to_owned().into();
+        json
+    }
+}
+
+fn is_token_parseable(token: &str) -> bool {
+    match Regex::new(r"^[A-Za-z0-9]+$") {
+        Ok(r) => r.is_match(token),
+        Err(_) => false
+    }
+}
+
+/// Removes the health secret from the request, it must never reach the origin.
+/// Has to be called before anything can forward the request, even before reading config.
+pub fn take_secret(req: &mut Request) -> Option<String> {
+    req.remove_header_str(HEALTH_SECRET_HEADER)
+}
+
+pub fn is_authorized(provided: Option<String>, health: &HealthConfig) -> bool {
+    match (&health.secret, provided) {
+        (Some(secret), Some(provided)) => secure_eq(secret, &provided),
+        _ => false
+    }
+}
+
+/// Reports configuration errors as well, so `config` is the result of `Config::new`.
+pub fn health_report(req: &Request, health: &HealthConfig, config: Result<&Config, &BotdError>) -> Response {
+    // Health response structure
+    //  {
+    //      "healthy": true,
+    //      "config": { "valid": true, "error": null },
+    //      "token": { "present": true, "parseable": true },
+    //      "agent_version": "0.1",
+    //      "backends": {
+    //          "botd": { "defined": true, "reachable": true, "status": 404, "latency_ms": 21, "error": null },
+    //          ...
+    //  }}
+    let mut healthy = true;
+    let mut json_backends = JsonValue::new_object();
+    for backend in [BOTD_BACKEND_NAME, CDN_BACKEND_NAME, APP_BACKEND_NAME] {
+        let probe = Probe::run(req, backend);
+        log::debug!("[health] Backend {} probed in {} ms", backend, probe.latency_ms);
+        healthy &= probe.is_ok();
+        json_backends[backend] = probe.to_json();
+    }
+    let token = health.token.to_owned().unwrap_or_default();
+    let token_present = !token.is_empty();
+    let token_parseable = is_token_parseable(&token);
+    healthy &= token_present && token_parseable;
+    healthy &= config.is_ok();
+
+    let mut json_config = JsonValue::new_object();
+    json_config["valid"] = config.is_ok().into();
+    json_config["error"] = config.err().map(|e| e.to_string()).into();
+
+    let mut json_token = JsonValue::new_object();
+    json_token["present"] = token_present.into();
+    json_token["parseable"] = token_parseable.into();
+    let mut json = JsonValue::new_object();
+    json["healthy"] = healthy.into();
+    json["config"] = json_config;
+    json["token"] = json_token;
+    json["agent_version"] = health.agent_version.to_owned().into();
+    json["backends"] = json_backends;
+
+    let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    Response::from_status(status)
+        .with_header(CONTENT_TYPE, "application/json")
+        .with_header(CACHE_CONTROL, "no-store")
+        .with_body(json.dump())
+}
@@ -1,33 +1,197 @@
 use regex::Regex;
+use serde_json::{Map, Value};
 use crate::PATH_HASH;
 use crate::config::Config;
 use crate::error::BotdError;
+use crate::redact::redact_token;
 use BotdError::{RegexSyntax, WrongHTML};
 
-pub fn inject_script(html: &str, config: &Config) -> Result<String, BotdError> {
-    log::debug!("[inject] Inject script with token: {}", config.token);
-    let min = if config.debug { String::new() } else { String::from(".min") };
-    let script_src = format!("/{}/dist/v{}/esm{}.js", PATH_HASH, config.agent_version, min);
-    let script = format!("
-    <script>
+/// DOM event dispatched on `document` when detection finishes.
+const RESULT_EVENT: &str = "botd:result";
+
+/// When the injected script starts bot detection.
+#[derive(Clone, Copy, PartialEq)]
+pub enum AgentTrigger {
+    /// Right after the page is parsed.
+    Load,
+    /// When the browser is idle.
+    Idle,
+    /// On the first user interaction.
+    Interaction
+}
+
+impl AgentTrigger {
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "idle" => AgentTrigger::Idle,
+            "interaction" => AgentTrigger::Interaction,
+            _ => AgentTrigger::Load
+        }
+    }
+
+    fn script(&self) -> &'static str {
+        match self {
+            AgentTrigger::Load => "getResults()",
+            AgentTrigger::Idle => "(window.requestIdleCallback || function (f) { setTimeout(f, 1) })(getResults)",
+            AgentTrigger::Interaction => "
+        (function () {
+            var events = ['pointerdown', 'keydown', 'touchstart', 'scroll'];
+            function start() {
+                events.forEach(e => removeEventListener(e, start, true));
+                getResults();
+            }
+            events.forEach(e => addEventListener(e, start, {capture: true, passive: true}));
+        })()"
+        }
+    }
+}
+
+/// Parses extra `Botd.load` options, they must be a JSON object.
+pub fn parse_load_options(value: &str) -> Option<Map<String, Value>> {
+    match serde_json::from_str(value) {
+        Ok(Value::Object(o)) => Some(o),
+        _ => {
+            log::error!("[inject] Load options are not a JSON object, ignored");
+            None
+        }
+    }
+}
+
+fn load_options(config: &Config) -> String {
+    let mut options = config.agent_load_options.to_owned().unwrap_or_default();
+    // integration options can't be overridden
+    options.insert(String::from("token"), Value::from(config.token.to_owned()));
+    options.insert(String::from("endpoint"), Value::from(PATH_HASH));
+    options.insert(String::from("mode"), Value::from("integration"));
+    // keep `</script>` in string values from closing the script tag
+    Value::Object(options).to_string().replace("</", "<\\/")
+}
+
+/// Where the agent is inserted into the page.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Placement {
+    HeadStart,
+    HeadEnd,
+    BodyEnd
+}
+
+impl Placement {
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "head-end" => Placement::HeadEnd,
+            "body-end" => Placement::BodyEnd,
+            _ => Placement::HeadStart
+        }
+    }
+
+    /// Position in the page to insert at.
+    fn find(&self, html: &str) -> Result<Option<usize>, BotdError> {
+        let re = match self {
+            Placement::HeadStart => r"(?i)<head(\s[^>]*)?>",
+            Placement::HeadEnd => r"(?i)</head\s*>",
+            Placement::BodyEnd => r"(?i)</body\s*>"
+        };
+        let r = Regex::new(re).map_err(|_| RegexSyntax(String::from(re)))?;
+        Ok(match self {
+            Placement::HeadStart => r.find(html).map(|m| m.end()),
+            Placement::HeadEnd => r.find(html).map(|m| m.start()),
+            Placement::BodyEnd => r.find_iter(html).last().map(|m| m.start())
+        })
+    }
+}
+
+/// How the agent loader gets to the page.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Inline script with dynamic import.
+    Inline,
+    /// External `async` module script.
+    External,
+    /// `Link: rel=modulepreload` header for the agent and deferred external loader.
+    Preload
+}
+
+impl Strategy {
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "external" => Strategy::External,
+            "preload" => Strategy::Preload,
+            _ => Strategy::Inline
+        }
+    }
+}
+
+fn agent_file(config: &Config) -> &'static str {
+    if config.debug { "esm.js" } else { "esm.min.js" }
+}
+
+fn agent_src(config: &Config) -> String {
+    format!("/{}/dist/v{}/{}", PATH_HASH, config.agent_version, agent_file(config))
+}
+
+fn agent_integrity(config: &Config) -> Option<&str> {
+    config.agent_integrity(&config.agent_version, agent_file(config))
+}
+
+/// Script which loads the agent and starts detection, inline or served at `/{hash}/loader.js`.
+pub fn loader_script(config: &Config, trigger: AgentTrigger) -> String {
+    format!("
+        function notify(detail) {{
+            document.dispatchEvent(new CustomEvent(\'{}\', {{ detail: detail }}))
+        }}
         function getResults() {{
             import(\'{}\')
-            .then( Botd => Botd.load({{
-                token:\'{}\',
-                endpoint:\'{}\',
-                mode:\'integration\'
-            }}))
-            .then( detector => detector.detect()) }}
-        getResults()
-    </script>", script_src, config.token, PATH_HASH);
-    let mut result = html.to_owned();
-    let re = r"(<head.*>)";
-    if let Ok(r) = Regex::new(re) {
-        if let Some(m) = r.find(html) {
-            result.insert_str(m.end(), script.as_str());
-            return Ok(result);
-        }
-        return Err(WrongHTML);
+            .then( Botd => Botd.load({}))
+            .then( detector => detector.detect())
+            .then( result => notify({{ requestId: result && result.requestId, result: result }}))
+            .catch( error => notify({{ error: String(error) }})) }}
+        {}
+    ", RESULT_EVENT, agent_src(config), load_options(config), trigger.script())
+}
+
+/// Value of `Link` header for the initial response, only with `preload` strategy.
+/// With configured integrity the agent is preloaded by the `modulepreload` tag instead,
+/// so that the integrity is checked.
+pub fn preload_link(config: &Config) -> Option<String> {
+    if config.agent_strategy != Strategy::Preload || agent_integrity(config).is_some() {
+        return None;
+    }
+    Some(format!("<{}>; rel=modulepreload", agent_src(config)))
+}
+
+pub fn inject_script(html: &str, config: &Config) -> Result<String, BotdError> {
+    inject(html, config, config.agent_strategy, config.agent_trigger)
+}
+
+/// Injects the agent into the challenge page, detection must start right away and not depend on the loader.
+pub fn inject_challenge(html: &str, config: &Config) -> Result<String, BotdError> {
+    inject(html, config, Strategy::Inline, AgentTrigger::Load)
+}
+
+fn inject(html: &str, config: &Config, strategy: Strategy, trigger: AgentTrigger) -> Result<String, BotdError> {
+    log::debug!("[inject] Inject script with token: {}", redact_token(&config.token));
+    let script_src = agent_src(config);
+    // dynamic import has no integrity attribute, the preloaded module is checked and reused by import
+    let preload = match agent_integrity(config) {
+        Some(i) => format!("
+    <link rel=\"modulepreload\" href=\"{}\" integrity=\"{}\">", script_src, i),
+        _ => String::new()
+    };
+    let loader = match strategy {
+        Strategy::Inline => format!("
+    <script>{}</script>", loader_script(config, trigger)),
+        Strategy::External => format!("
+    <script type=\"module\" async src=\"/{}/loader.js\"></script>", PATH_HASH),
+        Strategy::Preload => format!("
+    <script type=\"module\" src=\"/{}/loader.js\"></script>", PATH_HASH)
+    };
+    let script = format!("{}{}", preload, loader);
+    match config.agent_placement.find(html)? {
+        Some(position) => {
+            let mut result = html.to_owned();
+            result.insert_str(position, script.as_str());
+            Ok(result)
+        },
+        _ => Err(WrongHTML)
     }
-    Err(RegexSyntax(String::from(re)))
-}
\ No newline at end of file
+}
@@ -6,21 +6,43 @@ mod error;
 mod utils;
 mod botd;
 mod edge;
+mod health;
+mod trace;
+mod redact;
+mod client;
+mod backend;
+mod cache;
+mod verdict;
+mod protocol;
+mod proxy;
+mod assets;
+mod routes;
+mod cdn;
+mod challenge;
 
 use std::panic;
 use fastly::{Error, Request, Response};
-use fastly::http::header::{ACCEPT_ENCODING, SET_COOKIE};
-use fastly::http::StatusCode;
+use fastly::http::header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_TYPE, LINK, SET_COOKIE};
+use fastly::http::{Method, StatusCode};
 use botd::BotDetector;
 use edge::EdgeDetect;
-use BotdError::SendError;
-use crate::BotdError::CDNRedirectError;
-use crate::config::{Config, APP_BACKEND_NAME, BOTD_BACKEND_NAME, CDN_BACKEND_NAME};
-use crate::utils::{is_static_requested, make_cookie, is_favicon_requested, get_e_tld_plus_one};
+use crate::config::{Config, HealthConfig, APP_BACKEND_NAME};
+use crate::utils::{make_request_id_cookie, get_e_tld_plus_one};
+use crate::assets::{is_static_requested, is_favicon_requested, is_navigation_requested};
+use crate::routes::{is_upgrade_requested, RouteHandler};
+use crate::cdn::{agent_path, embedded_agent, fetch_agent, verify_integrity};
+use crate::challenge::{can_challenge, challenge_response, is_suspicious};
 use crate::detector::Detect;
-use crate::injector::inject_script;
+use crate::injector::{inject_script, loader_script, preload_link};
 use crate::request_id::RequestId;
-use crate::error::{handle_error, BotdError, panic_hook};
+use crate::error::{handle_error, report_error, panic_hook, BotdError};
+use crate::health::{health_report, is_authorized, take_secret};
+use crate::trace::Trace;
+use crate::redact::{redact_cookie, redact_ip};
+use crate::backend::send_to_botd;
+use crate::verdict::Verdict;
+use crate::protocol::parse_detect_response;
+use crate::proxy::{allowed_origin, filter_response, is_preflight, method_not_allowed, preflight_response, read_capped_body};
 
 const PATH_HASH: &str = "2f70092c";
 const CDN_DIST_PATH: &str = "/2f70092c/dist";
@@ -28,122 +50,378 @@ const CDN_DIST_PATH: &str = "/2f70092c/dist";
 pub const REQUEST_ID_HEADER_COOKIE: &str = "botd-request-id";
 pub const REQUEST_STATUS_HEADER: &str = "botd-request-status";
 pub const ERROR_DESCRIPTION_HEADER: &str = "botd-error-description";
+/// Request status of finished bot detection, other statuses come with an error description.
+pub const PROCESSED_STATUS: &str = "processed";
 pub const CLIENT_IP_HEADER: &str = "botd-client-ip";
 
-fn init_req_handler(mut req: Request, config: &Config) -> Result<Response, Error> {
+fn init_req_handler(mut req: Request, config: &Config, trace: &mut Trace) -> Result<Response, Error> {
     log::info!("[main] Initial request, starting edge detect");
+    trace.route("init");
     let domain = get_e_tld_plus_one(&req);
     let mut req_with_botd_headers = req.clone_with_body();
     req_with_botd_headers.remove_header(ACCEPT_ENCODING);
     let req_id = match EdgeDetect::make(&mut req_with_botd_headers, config) {
         Ok(d) => d.req_id,
-        Err(e) => return handle_error(req, e, Some(config), true)
+        Err(e) => return handle_error(req, e, Some(config), true, trace)
     };
     log::debug!("[main] Edge detect request id: {}", req_id);
+    trace.req_id(&req_id);
+    trace.detection(&req_with_botd_headers);
+    trace.decision("forward");
     let beresp = req_with_botd_headers.send(APP_BACKEND_NAME)?;
+    let cookie = make_request_id_cookie(req_id, domain, &config.cookie);
+    let is_html = beresp.get_header_str(CONTENT_TYPE).map_or(false, |t| t.to_lowercase().starts_with("text/html"));
+    if !is_html {
+        log::debug!("[main] Response is not HTML, script is not inserted");
+        return Ok(beresp.with_header(SET_COOKIE, cookie));
+    }
     let beresp_clone = beresp.clone_without_body();
     log::debug!("[main] Insert botd script");
-    let body = beresp.into_body_str();
-    let new_body = match inject_script(&body, config) {
+    let body = beresp.into_body_bytes();
+    let new_body = match inject_script(&String::from_utf8_lossy(&body), config) {
         Ok(b) => b,
-        Err(e) => return handle_error(req, e, Some(config), true)
+        Err(e) => {
+            // detection has succeeded, so the page is served as is, without the agent
+            report_error(&mut req, &e, Some(config), trace);
+            trace.decision("forward-with-error");
+            return Ok(beresp_clone
+                .with_header(SET_COOKIE, cookie)
+                .with_body(body));
+        }
     };
-    let cookie = make_cookie(REQUEST_ID_HEADER_COOKIE, req_id, domain);
-    log::debug!("[main] Set cookie to initial response: {}", cookie);
-    Ok(beresp_clone
+    log::debug!("[main] Set cookie to initial response: {}", redact_cookie(&cookie));
+    let mut resp = beresp_clone
         .with_header(SET_COOKIE, cookie)
-        .with_body(new_body))
+        .with_body(new_body);
+    if let Some(link) = preload_link(config) {
+        resp.append_header(LINK, link);
+    }
+    Ok(resp)
+}
+
+fn loader_req_handler(config: &Config, trace: &mut Trace) -> Result<Response, Error> {
+    const LOADER_MAX_AGE: u32 = 300;
+    log::info!("[main] Loader request => served loader script");
+    trace.route("loader");
+    trace.decision("skip");
+    Ok(Response::from_body(loader_script(config, config.agent_trigger))
+        .with_header(CONTENT_TYPE, "application/javascript; charset=utf-8")
+        .with_header(CACHE_CONTROL, format!("public, max-age={}", LOADER_MAX_AGE)))
 }
 
-fn detect_req_handler(req: Request, config: &Config) -> Result<Response, Error> {
+fn detect_req_handler(mut req: Request, config: &Config, trace: &mut Trace) -> Result<Response, Error> {
     log::info!("[main] Detect request => redirecting to Botd");
+    trace.route("detect");
+    let origin = match allowed_origin(&req, config) {
+        Some(o) => o,
+        _ => {
+            trace.decision("reject");
+            return Ok(Response::from_status(StatusCode::FORBIDDEN));
+        }
+    };
+    if is_preflight(&req) {
+        trace.decision("preflight");
+        return Ok(preflight_response(&origin));
+    }
+    if req.get_method() != Method::POST {
+        trace.decision("reject");
+        return Ok(method_not_allowed());
+    }
+    let body = match read_capped_body(&mut req, config) {
+        Some(b) => b,
+        _ => {
+            trace.decision("reject");
+            return Ok(Response::from_status(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+    };
     let domain = get_e_tld_plus_one(&req);
     let err_req = req.clone_without_body();
-    let mut botd_resp = match req
-        .with_path("/api/v1/detect")
+    let detect_req = req
+        .with_path(&config.api_version.path("detect"))
         .with_header(CLIENT_IP_HEADER, config.ip.to_owned())
-        .send(BOTD_BACKEND_NAME) {
+        .with_body(body);
+    let botd_resp = match send_to_botd(detect_req, config, false) {
         Ok(r) => r,
-        Err(e) => return handle_error(err_req, SendError(Box::new(e)), Some(config), false)
+        Err(e) => return handle_error(err_req, e, Some(config), false, trace)
     };
-    let botd_resp_clone = botd_resp.clone_with_body();
-    let req_id = RequestId::from_resp_body(botd_resp_clone).unwrap_or_default();
-    let cookie = make_cookie(REQUEST_ID_HEADER_COOKIE, req_id, domain);
-    log::debug!("[main] Set cookie to detect response: {}", cookie);
-    Ok(botd_resp.with_header(SET_COOKIE, cookie))
+    trace.decision("proxy");
+    let (resp, body) = filter_response(botd_resp, &origin);
+    let req_id = std::str::from_utf8(&body).ok()
+        .and_then(parse_detect_response)
+        .map(|r| r.request_id)
+        .filter(|id| RequestId::is_valid(id));
+    let resp = resp.with_body(body);
+    match req_id {
+        Some(id) => {
+            trace.req_id(&id);
+            let cookie = make_request_id_cookie(id, domain, &config.cookie);
+            log::debug!("[main] Set cookie to detect response: {}", redact_cookie(&cookie));
+            Ok(resp.with_header(SET_COOKIE, cookie))
+        },
+        _ => {
+            log::error!("[main] No request id in detect response, cookie is not set");
+            Ok(resp)
+        }
+    }
 }
 
-fn dist_req_handler(req: Request, config: &Config) -> Result<Response, Error> {
-    log::info!("[main] Script request => redirecting to CDN");
-    let err_req = req.clone_without_body();
-    let cdn_req = req.clone_without_body();
-    let req_path = String::from(req.get_path());
-    let cut_path = req_path[CDN_DIST_PATH.len()..].to_owned();
-    let path = format!("/botd{}", cut_path);
-    match req
-        .with_path(path.as_str())
-        .send(CDN_BACKEND_NAME) {
-        Ok(r) => {
-            if r.get_status() == StatusCode::FOUND {
-                if let Some(l) = r.get_header("location") {
-                    let actual_path = l.to_str().unwrap_or_default();
-                    match cdn_req
-                        .with_path(actual_path)
-                        .send(CDN_BACKEND_NAME) {
-                        Ok(cdn_resp) => Ok(cdn_resp),
-                        Err(e) => handle_error(err_req, SendError(Box::new(e)), Some(config), false)
-                    }
-                } else {
-                    handle_error(err_req, CDNRedirectError, Some(config), false)
-                }
-            } else { Ok(r) }
-        },
-        Err(e) => handle_error(err_req, SendError(Box::new(e)), Some(config), false)
+fn dist_req_handler(mut req: Request, config: &Config, trace: &mut Trace) -> Result<Response, Error> {
+    log::info!("[main] Script request => proxying to CDN");
+    trace.route("dist");
+    let path = match agent_path(&req.get_path()[CDN_DIST_PATH.len()..]) {
+        Some(p) => p,
+        _ => {
+            log::info!("[main] Not an agent file => not found");
+            trace.decision("reject");
+            return Ok(Response::from_status(StatusCode::NOT_FOUND));
+        }
+    };
+    if config.agent_self_host {
+        if let Some(r) = embedded_agent(&path, config) {
+            log::info!("[main] Self-hosted agent => served embedded copy");
+            trace.decision("embedded");
+            return Ok(r);
+        }
+        log::error!("[main] Agent self-hosting is enabled, but the build has no embedded agent");
+    }
+    trace.decision("proxy");
+    let result = fetch_agent(&req, path.to_owned(), config).and_then(|r| verify_integrity(r, &path, config));
+    if result.as_ref().map_or(true, |r| r.get_status().is_server_error()) {
+        if let Some(r) = embedded_agent(&path, config) {
+            if let Err(e) = &result {
+                report_error(&mut req, e, Some(config), trace);
+            }
+            log::info!("[main] CDN failed => served embedded agent");
+            trace.decision("embedded-fallback");
+            return Ok(r);
+        }
+    }
+    match result {
+        Ok(r) => Ok(r),
+        Err(e) => handle_error(req, e, Some(config), false, trace)
     }
 }
 
-fn favicon_req_handler(req: Request, config: &Config) -> Result<Response, Error> {
+fn favicon_req_handler(req: Request, config: &Config, trace: &mut Trace) -> Result<Response, Error> {
     log::info!("[main] Favicon request => starting edge detect");
+    trace.route("favicon");
+    let domain = get_e_tld_plus_one(&req);
+    let prev_req_id = RequestId::from_req_cookie(&req, &config.cookie.request_id_name()).ok();
     let mut edge_req = req.clone_without_body();
     match EdgeDetect::make(&mut edge_req, config) {
-        // TODO: Fix edge detect cookie race
-        Ok(_) => Ok(req.send(APP_BACKEND_NAME)?),
-        Err(e) => handle_error(req, e, Some(config), true)
+        Ok(d) => {
+            trace.detection(&edge_req);
+            trace.decision("forward");
+            // The initial request has already set the cookie, keep it, so that the session
+            // has a single request id. New edge detection is chained to it by `previous_request_id`.
+            if let Some(id) = prev_req_id {
+                log::debug!("[main] Reuse request id from cookie: {}, edge detect request id: {}", id, d.req_id);
+                trace.req_id(&id);
+                return Ok(req.send(APP_BACKEND_NAME)?);
+            }
+            trace.req_id(&d.req_id);
+            let cookie = make_request_id_cookie(d.req_id, domain, &config.cookie);
+            log::debug!("[main] Set cookie to favicon response: {}", redact_cookie(&cookie));
+            let mut resp = req.send(APP_BACKEND_NAME)?;
+            resp.append_header(SET_COOKIE, cookie);
+            Ok(resp)
+        },
+        Err(e) => handle_error(req, e, Some(config), true, trace)
+    }
+}
+
+fn health_req_handler(
+    req: Request,
+    secret: Option<String>,
+    config: Result<&Config, &BotdError>,
+    trace: &mut Trace
+) -> Result<Response, Error> {
+    trace.route("health");
+    if req.get_method() != Method::GET {
+        return Ok(Response::from_status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let health = HealthConfig::read();
+    if !is_authorized(secret, &health) {
+        log::info!("[main] Health request without valid secret => forbidden");
+        return Ok(Response::from_status(StatusCode::FORBIDDEN));
+    }
+    if let Err(e) = config {
+        log::error!("[main] Health request with invalid config: {}", e.to_string());
     }
+    log::info!("[main] Health request => probing backends");
+    Ok(health_report(&req, &health, config))
 }
 
-fn static_req_handler(req: Request) -> Result<Response, Error> {
+fn static_req_handler(req: Request, trace: &mut Trace) -> Result<Response, Error> {
     log::info!("[main] Static request => skipped bot detection");
+    trace.route("static");
+    trace.decision("skip");
+    Ok(req.send(APP_BACKEND_NAME)?)
+}
+
+fn upgrade_req_handler(req: Request, trace: &mut Trace) -> Result<Response, Error> {
+    log::info!("[main] Upgrade request => passed through");
+    trace.route("upgrade");
+    trace.decision("skip");
     Ok(req.send(APP_BACKEND_NAME)?)
 }
 
-fn non_static_req_handler(mut req: Request, config: &Config) -> Result<Response, Error> {
+fn method_bypass_req_handler(req: Request, trace: &mut Trace) -> Result<Response, Error> {
+    log::info!("[main] {} request => skipped bot detection", req.get_method_str());
+    trace.route("method");
+    trace.decision("skip");
+    Ok(req.send(APP_BACKEND_NAME)?)
+}
+
+fn block_req_handler(trace: &mut Trace) -> Result<Response, Error> {
+    log::info!("[main] Blocked by route");
+    trace.route("block");
+    trace.decision("block");
+    Ok(Response::from_status(StatusCode::FORBIDDEN))
+}
+
+fn results_req_handler(req: Request, config: &Config, trace: &mut Trace) -> Result<Response, Error> {
+    // tag mode can't block, so requests which must fail closed wait for the results
+    if config.tag_only && !config.is_fail_closed(&req) {
+        return tag_req_handler(req, config, trace);
+    }
+    non_static_req_handler(req, config, trace, false)
+}
+
+fn refresh_cookie(resp: &mut Response, req_id: &str, domain: Option<String>, config: &Config) {
+    if !config.cookie.sliding {
+        return;
+    }
+    let cookie = make_request_id_cookie(String::from(req_id), domain, &config.cookie);
+    log::debug!("[main] Refresh cookie on response: {}", redact_cookie(&cookie));
+    resp.append_header(SET_COOKIE, cookie);
+}
+
+fn challenge_page(req: Request, config: &Config, trace: &mut Trace) -> Result<Response, Error> {
+    log::info!("[main] No valid bot detection => challenge page");
+    trace.decision("challenge");
+    match challenge_response(&req, config) {
+        Ok(r) => Ok(r),
+        Err(e) => handle_error(req, e, Some(config), true, trace)
+    }
+}
+
+fn non_static_req_handler(mut req: Request, config: &Config, trace: &mut Trace, challenge: bool) -> Result<Response, Error> {
     log::info!("[main] Not static request => do bot detection");
+    trace.route(if challenge { "challenge" } else { "results" });
+    let challenge = challenge && can_challenge(&req, config);
+    let domain = get_e_tld_plus_one(&req);
+    if let Some(v) = Verdict::verify(&req, config) {
+        log::info!("[main] Valid verdict cookie => skipped results lookup");
+        v.transfer_headers(&mut req);
+        if challenge && is_suspicious(&req) {
+            return challenge_page(req, config, trace);
+        }
+        trace.req_id(&v.req_id);
+        trace.detection(&req);
+        trace.decision("forward-verified");
+        let mut resp = req.send(APP_BACKEND_NAME)?;
+        refresh_cookie(&mut resp, &v.req_id, domain, config);
+        return Ok(resp);
+    }
+    if challenge && RequestId::from_req(&req, config).is_err() {
+        return challenge_page(req, config, trace);
+    }
     match BotDetector::make(&mut req, config) {
-        Ok(_) => Ok(req.send(APP_BACKEND_NAME)?),
-        Err(e) => handle_error(req, e, Some(config), true)
+        Ok(d) => {
+            trace.req_id(&d.req_id);
+            trace.detection(&req);
+            if challenge && is_suspicious(&req) {
+                return challenge_page(req, config, trace);
+            }
+            trace.decision("forward");
+            let verdict_cookie = Verdict::from_req(&req, &d.req_id, config)
+                .and_then(|v| v.make_cookie(domain.to_owned(), config));
+            let mut resp = req.send(APP_BACKEND_NAME)?;
+            if let Some(c) = verdict_cookie {
+                log::debug!("[main] Set verdict cookie to response: {}", redact_cookie(&c));
+                resp.append_header(SET_COOKIE, c);
+            }
+            refresh_cookie(&mut resp, &d.req_id, domain, config);
+            Ok(resp)
+        },
+        Err(e) => handle_error(req, e, Some(config), true, trace)
+    }
+}
+
+fn tag_req_handler(mut req: Request, config: &Config, trace: &mut Trace) -> Result<Response, Error> {
+    log::info!("[main] Not static request => do bot detection in parallel with origin request");
+    trace.route("results");
+    let pending = BotDetector::start(&req, config);
+    let origin = req.clone_with_body().send_async(APP_BACKEND_NAME)?;
+    // the origin request is already sent, so the verdict is only collected, not forwarded
+    let mut verdict_req = req.clone_without_body();
+    let req_id = match pending.and_then(|p| BotDetector::wait(p, &mut verdict_req, config)) {
+        Ok(d) => {
+            log::info!("[main] Tag-only bot detection finished for request id: {}", d.req_id);
+            trace.req_id(&d.req_id);
+            trace.detection(&verdict_req);
+            trace.decision("tag");
+            Some(d.req_id)
+        },
+        Err(e) => {
+            report_error(&mut req, &e, Some(config), trace);
+            trace.decision("tag-with-error");
+            None
+        }
+    };
+    let mut resp = origin.wait()?;
+    if let Some(id) = req_id {
+        refresh_cookie(&mut resp, &id, get_e_tld_plus_one(&req), config);
     }
+    Ok(resp)
 }
 
 #[fastly::main]
 fn main(mut req: Request) -> Result<Response, Error> {
     panic::set_hook(panic_hook());
     req.set_pass(true);
+    let debug_secret = Trace::take_secret(&mut req);
+    let health_secret = take_secret(&mut req);
 
-    let config = match Config::new(&req) {
+    let config = Config::new(&req);
+    // health is reported with invalid config as well, catching misconfiguration is what it's for
+    if req.get_path() == format!("/{}/health", PATH_HASH) {
+        let mut trace = match &config {
+            Ok(c) => Trace::from_secret(debug_secret, c),
+            _ => Trace::disabled()
+        };
+        return health_req_handler(req, health_secret, config.as_ref(), &mut trace).map(|r| trace.apply(r));
+    }
+    let config = match config {
         Ok(c) => c,
-        Err(e) => return handle_error(req, e, None, true)
+        Err(e) => return handle_error(req, e, None, true, &mut Trace::disabled())
     };
+    let mut trace = Trace::from_secret(debug_secret, &config);
 
     log::info!("[main] New request {}", req.get_url_str());
-    log::debug!("[main] IP address: {}, headers: {:?}", config.ip, req.get_header_names_str());
-
-    return match req.get_path() {
-        "/" => init_req_handler(req, &config),
-        p if p == format!("/{}/detect", PATH_HASH) => detect_req_handler(req, &config),
-        p if p.starts_with(CDN_DIST_PATH) => dist_req_handler(req, &config),
-        _ if is_favicon_requested(&req) => favicon_req_handler(req, &config),
-        _ if is_static_requested(&req) => static_req_handler(req),
-        _ => non_static_req_handler(req, &config)
+    log::debug!("[main] IP address: {}, headers: {:?}", redact_ip(&config.ip, &config), req.get_header_names_str());
+
+    // Integration endpoints come first, so that site routes can't shadow them
+    let resp = match req.get_path() {
+        p if p == format!("/{}/detect", PATH_HASH) => detect_req_handler(req, &config, &mut trace),
+        p if p == format!("/{}/loader.js", PATH_HASH) => loader_req_handler(&config, &mut trace),
+        p if p.starts_with(CDN_DIST_PATH) => dist_req_handler(req, &config, &mut trace),
+        _ => match config.routes.find(&req) {
+            Some(RouteHandler::Block) => block_req_handler(&mut trace),
+            // upgrades and bypassed methods can't skip blocking, strict methods and fail closed mode
+            _ if is_upgrade_requested(&req) && !config.is_fail_closed(&req) => upgrade_req_handler(req, &mut trace),
+            _ if config.is_bypass_method(&req) => method_bypass_req_handler(req, &mut trace),
+            Some(RouteHandler::Init) => init_req_handler(req, &config, &mut trace),
+            Some(RouteHandler::EdgeDetect) => favicon_req_handler(req, &config, &mut trace),
+            Some(RouteHandler::Results) => results_req_handler(req, &config, &mut trace),
+            Some(RouteHandler::Bypass) => static_req_handler(req, &mut trace),
+            Some(RouteHandler::Challenge) => non_static_req_handler(req, &config, &mut trace, true),
+            None if is_favicon_requested(&req, &config.assets) => favicon_req_handler(req, &config, &mut trace),
+            None if is_static_requested(&req, &config.assets) => static_req_handler(req, &mut trace),
+            None if config.inject_on_navigation && is_navigation_requested(&req) => init_req_handler(req, &config, &mut trace),
+            None => results_req_handler(req, &config, &mut trace)
+        }
     };
+    resp.map(|r| trace.apply(r))
 }
\ No newline at end of file
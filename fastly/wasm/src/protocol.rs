@@ -0,0 +1,159 @@
+use fastly::Response;
+use serde::Deserialize;
+use serde_json::Value;
+use crate::detector::{check_botd_resp, RESULT_HEADERS};
+use crate::error::BotdError;
+use crate::{ERROR_DESCRIPTION_HEADER, PROCESSED_STATUS, REQUEST_ID_HEADER_COOKIE, REQUEST_STATUS_HEADER};
+use BotdError::{JsonParse, NoErrorDescriptionInHeaders, UnsupportedApiVersion};
+
+/// Header with the whole JSON result, so that the origin gets fields which don't fit in separate headers.
+pub const RESULT_JSON_HEADER: &str = "botd-result";
+
+/// How botd returns bot detection results.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// Results are returned in `botd-*` response headers.
+    Header,
+    /// Results are returned in JSON response body.
+    Json
+}
+
+impl Protocol {
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "json" => Protocol::Json,
+            _ => Protocol::Header
+        }
+    }
+
+    pub fn query(&self) -> &'static str {
+        match self {
+            Protocol::Header => "header",
+            Protocol::Json => "json"
+        }
+    }
+}
+
+/// Version of botd API, used to build endpoint paths.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ApiVersion {
+    V1
+}
+
+impl ApiVersion {
+    pub fn parse(value: &str) -> Result<Self, BotdError> {
+        match value {
+            "v1" => Ok(ApiVersion::V1),
+            v => Err(UnsupportedApiVersion(String::from(v)))
+        }
+    }
+
+    pub fn path(&self, endpoint: &str) -> String {
+        match self {
+            ApiVersion::V1 => format!("/api/v1/{}", endpoint)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DetectorResult {
+    pub status: String,
+    pub probability: Option<f64>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResultsResponse {
+    pub request_id: String,
+    pub request_status: String,
+    pub error_description: Option<String>,
+    pub automation_tool: Option<DetectorResult>,
+    pub search_bot: Option<DetectorResult>,
+    pub browser_spoofing: Option<DetectorResult>,
+    pub vm: Option<DetectorResult>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DetectResponse {
+    pub request_id: String
+}
+
+/// Bot detection results in the form they are sent to the origin, regardless of the protocol.
+pub struct BotdResults {
+    pub req_id: Option<String>,
+    pub status: String,
+    pub headers: Vec<(&'static str, String)>
+}
+
+impl BotdResults {
+    fn from_headers(resp: &Response) -> Result<Self, BotdError> {
+        check_botd_resp(resp)?;
+        let headers: Vec<(&'static str, String)> = RESULT_HEADERS.iter()
+            .filter_map(|name| Some((*name, String::from(resp.get_header_str(*name)?))))
+            .collect();
+        let find = |name: &str| headers.iter().find(|(n, _)| *n == name).map(|(_, v)| v.to_owned());
+        Ok(BotdResults {
+            req_id: find(REQUEST_ID_HEADER_COOKIE),
+            status: find(REQUEST_STATUS_HEADER).unwrap_or_default(),
+            headers
+        })
+    }
+
+    fn from_json(body: &str) -> Result<Self, BotdError> {
+        let json: Value = serde_json::from_str(body).map_err(|e| JsonParse(e.to_string()))?;
+        let results: ResultsResponse = serde_json::from_value(json.clone()).map_err(|e| JsonParse(e.to_string()))?;
+        if results.request_status != PROCESSED_STATUS && results.error_description.is_none() {
+            return Err(NoErrorDescriptionInHeaders);
+        }
+        let mut headers = vec![
+            (REQUEST_ID_HEADER_COOKIE, results.request_id.to_owned()),
+            (REQUEST_STATUS_HEADER, results.request_status.to_owned()),
+            (RESULT_JSON_HEADER, json.to_string())
+        ];
+        if let Some(e) = &results.error_description {
+            headers.push((ERROR_DESCRIPTION_HEADER, e.to_owned()));
+        }
+        let detectors = [
+            ("automation-tool", &results.automation_tool),
+            ("search-bot", &results.search_bot),
+            ("browser-spoofing", &results.browser_spoofing),
+            ("vm", &results.vm)
+        ];
+        for (name, result) in detectors.iter() {
+            if let Some(r) = result {
+                push_detector_headers(&mut headers, name, r);
+            }
+        }
+        Ok(BotdResults { req_id: Some(results.request_id), status: results.request_status, headers })
+    }
+}
+
+fn push_detector_headers(headers: &mut Vec<(&'static str, String)>, detector: &str, result: &DetectorResult) {
+    let values = [
+        ("status", Some(result.status.to_owned())),
+        ("prob", result.probability.map(|p| format!("{:.2}", p))),
+        ("type", result.kind.to_owned())
+    ];
+    for (suffix, value) in values.iter() {
+        let name = format!("botd-{}-{}", detector, suffix);
+        // result headers are static, take the name from the list so it can be sent to the origin as is
+        if let (Some(n), Some(v)) = (RESULT_HEADERS.iter().find(|h| **h == name), value) {
+            headers.push((*n, v.to_owned()));
+        }
+    }
+}
+
+/// Reads bot detection results from botd response according to the protocol.
+pub fn read_results(resp: Response, protocol: Protocol) -> Result<BotdResults, BotdError> {
+    match protocol {
+        Protocol::Header => BotdResults::from_headers(&resp),
+        Protocol::Json => BotdResults::from_json(&resp.into_body_str())
+    }
+}
+
+pub fn parse_detect_response(body: &str) -> Option<DetectResponse> {
+    serde_json::from_str(body).ok()
+}
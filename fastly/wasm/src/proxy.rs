@@ -0,0 +1,89 @@
+use std::io::Read;
+use fastly::{Request, Response};
+use fastly::http::{Method, StatusCode, Url};
+use fastly::http::header::{
+    ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ALLOW, CONTENT_LENGTH, ORIGIN, REFERER, VARY
+};
+use crate::config::Config;
+use crate::utils::get_host;
+
+/// Headers of botd detect response which are passed to the client.
+const RESPONSE_HEADERS_ALLOWLIST: [&str; 3] = ["content-type", "cache-control", "expires"];
+const ALLOWED_METHODS: &str = "POST, OPTIONS";
+
+fn origin_of(url: &str) -> Option<Url> {
+    let url = Url::parse(url).ok()?;
+    url.host_str()?;
+    Some(url)
+}
+
+/// Returns origin of the request from `Origin` or `Referer` header
+/// if it's the protected host or one of `detect_allowed_origins`.
+pub fn allowed_origin(req: &Request, config: &Config) -> Option<String> {
+    let url = match req.get_header_str(ORIGIN) {
+        Some(o) => origin_of(o)?,
+        _ => origin_of(req.get_header_str(REFERER)?)?
+    };
+    let origin = url.origin().ascii_serialization();
+    let is_same_host = url.host_str().map(String::from) == get_host(req);
+    if is_same_host || config.detect_allowed_origins.contains(&origin.to_lowercase()) {
+        return Some(origin);
+    }
+    log::error!("[proxy] Origin {} is not allowed", origin);
+    None
+}
+
+fn with_cors_headers(resp: Response, origin: &str) -> Response {
+    resp.with_header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+        .with_header(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")
+        .with_header(VARY, "Origin")
+}
+
+pub fn preflight_response(origin: &str) -> Response {
+    const PREFLIGHT_MAX_AGE: &str = "600";
+    with_cors_headers(Response::from_status(StatusCode::NO_CONTENT), origin)
+        .with_header(ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS)
+        .with_header(ACCESS_CONTROL_ALLOW_HEADERS, "content-type")
+        .with_header(ACCESS_CONTROL_MAX_AGE, PREFLIGHT_MAX_AGE)
+}
+
+pub fn method_not_allowed() -> Response {
+    Response::from_status(StatusCode::METHOD_NOT_ALLOWED).with_header(ALLOW, ALLOWED_METHODS)
+}
+
+pub fn is_preflight(req: &Request) -> bool {
+    req.get_method() == Method::OPTIONS
+}
+
+/// Reads the body, unless it's larger than `detect_max_body_bytes`.
+/// At most one byte over the limit is read, so a body without (or with a wrong) `Content-Length` isn't buffered whole.
+pub fn read_capped_body(req: &mut Request, config: &Config) -> Option<Vec<u8>> {
+    let declared = req.get_header_str(CONTENT_LENGTH).and_then(|l| l.parse::<u64>().ok());
+    if declared.unwrap_or_default() > config.detect_max_body_bytes {
+        log::error!("[proxy] Declared body length {:?} is too large", declared);
+        return None;
+    }
+    let mut body = Vec::new();
+    if let Err(e) = req.take_body().take(config.detect_max_body_bytes.saturating_add(1)).read_to_end(&mut body) {
+        log::error!("[proxy] Can't read body: {}", e);
+        return None;
+    }
+    if body.len() as u64 > config.detect_max_body_bytes {
+        log::error!("[proxy] Body is larger than {} bytes", config.detect_max_body_bytes);
+        return None;
+    }
+    Some(body)
+}
+
+/// Makes the client response from botd response, passing only allowlisted headers.
+pub fn filter_response(mut botd_resp: Response, origin: &str) -> (Response, Vec<u8>) {
+    let body = botd_resp.take_body_bytes();
+    let mut resp = Response::from_status(botd_resp.get_status());
+    for name in RESPONSE_HEADERS_ALLOWLIST.iter() {
+        if let Some(value) = botd_resp.get_header(*name) {
+            resp.set_header(*name, value);
+        }
+    }
+    (with_cors_headers(resp, origin), body)
+}
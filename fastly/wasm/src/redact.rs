@@ -0,0 +1,59 @@
+use sha2::{Digest, Sha256};
+use crate::config::Config;
+use crate::health::HEALTH_SECRET_HEADER;
+use crate::trace::DEBUG_SECRET_HEADER;
+
+const REDACTED: &str = "<redacted>";
+
+/// Keeps only a short prefix of the token, enough to tell tokens apart in logs.
+pub fn redact_token(token: &str) -> String {
+    const VISIBLE_PREFIX_LEN: usize = 4;
+    match token.get(..VISIBLE_PREFIX_LEN) {
+        Some(prefix) if token.len() > VISIBLE_PREFIX_LEN * 2 => format!("{}{}", prefix, REDACTED),
+        _ => String::from(REDACTED)
+    }
+}
+
+/// Keeps cookie names and attributes, hides the value of `Cookie` and `Set-Cookie` header strings.
+pub fn redact_cookie(cookie: &str) -> String {
+    cookie.split(';')
+        .map(|pair| {
+            let pair = pair.trim();
+            match pair.find('=') {
+                Some(i) if !is_cookie_attribute(&pair[..i]) => format!("{}={}", &pair[..i], REDACTED),
+                _ => String::from(pair)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+fn is_cookie_attribute(name: &str) -> bool {
+    const ATTRIBUTES: [&str; 5] = ["path", "domain", "expires", "max-age", "samesite"];
+    ATTRIBUTES.contains(&name.to_lowercase().as_str())
+}
+
+/// Returns the ip address as is, or its salted SHA-256 hash if `hash_ip` is enabled.
+pub fn redact_ip(ip: &str, config: &Config) -> String {
+    if !config.hash_ip {
+        return String::from(ip);
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(config.hash_ip_salt.as_bytes());
+    hasher.update(ip.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+pub fn is_header_allowed(name: &str, config: &Config) -> bool {
+    let name = name.to_lowercase();
+    if name == HEALTH_SECRET_HEADER || name == DEBUG_SECRET_HEADER {
+        return false;
+    }
+    if config.edge_headers_deny.contains(&name) {
+        return false;
+    }
+    match &config.edge_headers_allow {
+        Some(allow) => allow.contains(&name),
+        _ => true
+    }
+}
@@ -1,13 +1,39 @@
-use fastly::{Request, Response};
-use BotdError::{NoRequestIdInCookie, NoRequestIdInHeaders, ToStringCast};
+use fastly::Request;
+use BotdError::{NoRequestId, NoRequestIdInCookie, NoRequestIdInHeaders, ToStringCast};
 use crate::REQUEST_ID_HEADER_COOKIE;
 use crate::error::BotdError;
+use crate::config::Config;
 use fastly::http::header::COOKIE;
 use cookie::Cookie;
 use fastly::http::HeaderValue;
+use crate::protocol::parse_detect_response;
 
 pub struct RequestId;
 
+/// Where results lookup takes the request id from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RequestIdSource {
+    Cookie,
+    Header,
+    Query
+}
+
+impl RequestIdSource {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "cookie" => Some(RequestIdSource::Cookie),
+            "header" => Some(RequestIdSource::Header),
+            "query" => Some(RequestIdSource::Query),
+            _ => {
+                log::error!("[request_id] Unknown request id source {}, ignored", value);
+                None
+            }
+        }
+    }
+}
+
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
 fn find_in_cookie_str(cookies: &str, name: &str) -> Option<String> {
     let cookies = cookies.split(';');
     for c in cookies {
@@ -26,47 +52,67 @@ pub fn get_cookie(req: &Request, name: &str) -> Option<String> {
 }
 
 impl RequestId {
-    fn extract_from_body_str(body: String) -> Option<String> {
-        let json = json::parse(body.as_str()).ok()?;
-        if json.is_object() {
-            return json["requestId"].to_owned().take_string();
+    /// Checks that request id has botd format, so it's safe to pass it to botd and back to the client.
+    pub fn is_valid(req_id: &str) -> bool {
+        !req_id.is_empty()
+            && req_id.len() <= MAX_REQUEST_ID_LENGTH
+            && req_id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    }
+
+    fn validate(req_id: String, source: &str) -> Option<String> {
+        if Self::is_valid(&req_id) {
+            return Some(req_id);
         }
+        log::error!("[request_id] Invalid request id in {}, ignored", source);
         None
     }
 
+    fn extract_from_body_str(body: String) -> Option<String> {
+        Self::validate(parse_detect_response(body.as_str())?.request_id, "body")
+    }
+
     fn extract_from_header(header: &HeaderValue) -> Result<String, BotdError> {
         match header.to_str() {
-            Ok(r) => Ok(String::from(r)),
+            Ok(r) => Self::validate(String::from(r), "header").ok_or(NoRequestIdInHeaders),
             Err(_) => Err(ToStringCast(String::from("request id")))
         }
     }
 
-    pub fn from_resp_body(resp: Response) -> Option<String> {
-        let body = resp.into_body_str();
-        Self::extract_from_body_str(body)
-    }
-
     pub fn from_req_body(req: Request) -> Option<String> {
         let body = req.into_body_str();
         Self::extract_from_body_str(body)
     }
 
-    pub fn from_resp_header(resp: &Response) -> Result<String, BotdError> {
-        match resp.get_header(REQUEST_ID_HEADER_COOKIE) {
+    pub fn from_req_header(req: &Request, header_name: &str) -> Result<String, BotdError> {
+        return match req.get_header(header_name) {
             Some(r) => Ok(Self::extract_from_header(r)?),
             _ => Err(NoRequestIdInHeaders)
-        }
+        };
     }
 
-    pub fn from_req_header(req: &Request) -> Result<String, BotdError> {
-        return match req.get_header(REQUEST_ID_HEADER_COOKIE) {
-            Some(r) => Ok(Self::extract_from_header(r)?),
-            _ => Err(NoRequestIdInHeaders)
-        };
+    pub fn from_req_query(req: &Request, param: &str) -> Option<String> {
+        let value = req.get_url().query_pairs().find(|(k, _)| k == param)?.1.into_owned();
+        Self::validate(value, "query")
     }
 
-    pub fn from_req_cookie(req: &Request) -> Result<String, BotdError> {
-        match get_cookie(req, REQUEST_ID_HEADER_COOKIE) {
+    /// Takes the request id from configured sources in order of precedence.
+    pub fn from_req(req: &Request, config: &Config) -> Result<String, BotdError> {
+        for source in config.request_id_sources.iter() {
+            let req_id = match source {
+                RequestIdSource::Cookie => Self::from_req_cookie(req, &config.cookie.request_id_name()).ok(),
+                RequestIdSource::Header => Self::from_req_header(req, &config.request_id_header).ok(),
+                RequestIdSource::Query => config.request_id_query.as_ref().and_then(|p| Self::from_req_query(req, p))
+            };
+            if let Some(id) = req_id {
+                log::debug!("[request_id] Request id found in {:?}", source);
+                return Ok(id);
+            }
+        }
+        Err(NoRequestId)
+    }
+
+    pub fn from_req_cookie(req: &Request, cookie_name: &str) -> Result<String, BotdError> {
+        match get_cookie(req, cookie_name).and_then(|r| Self::validate(r, "cookie")) {
             Some(r) => Ok(r),
             _ => Err(NoRequestIdInCookie)
         }
@@ -85,9 +131,9 @@ impl RequestId {
     //     }
     // }
 
-    pub fn search_in_req(req: &mut Request) -> Option<String> {
-        let in_cookie = Self::from_req_cookie(req).ok();
-        let in_header = Self::from_req_header(req).ok();
+    pub fn search_in_req(req: &mut Request, cookie_name: &str) -> Option<String> {
+        let in_cookie = Self::from_req_cookie(req, cookie_name).ok();
+        let in_header = Self::from_req_header(req, REQUEST_ID_HEADER_COOKIE).ok();
         let in_body = Self::from_req_body(req.clone_with_body());
 
         if in_cookie.is_some() { in_cookie }
@@ -106,4 +152,34 @@ impl RequestId {
     //     else if in_body.is_some() { in_body }
     //     else { None }
     // }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn botd_request_ids_are_valid() {
+        assert!(RequestId::is_valid("0123456789abcdefABCDEF"));
+        assert!(RequestId::is_valid("a-b_c"));
+        assert!(RequestId::is_valid(&"a".repeat(MAX_REQUEST_ID_LENGTH)));
+    }
+
+    #[test]
+    fn request_ids_which_break_urls_are_invalid() {
+        for id in ["a&token=x", "a#b", "a=b", "a b", "a/b", "a%26b", "a;b", "ä"].iter() {
+            assert!(!RequestId::is_valid(id), "{}", id);
+        }
+    }
+
+    #[test]
+    fn empty_or_long_request_ids_are_invalid() {
+        assert!(!RequestId::is_valid(""));
+        assert!(!RequestId::is_valid(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1)));
+    }
+
+    #[test]
+    fn invalid_request_id_is_dropped() {
+        assert_eq!(RequestId::validate(String::from("abc"), "test"), Some(String::from("abc")));
+        assert_eq!(RequestId::validate(String::from("abc&x=1"), "test"), None);
+    }
+}
@@ -0,0 +1,152 @@
+use fastly::Request;
+use fastly::http::Method;
+use fastly::http::header::{CONNECTION, UPGRADE};
+use regex::Regex;
+use serde::Deserialize;
+use crate::error::BotdError;
+use BotdError::{InvalidRoutes, RegexSyntax};
+
+/// Default route table, the landing page gets the agent injected.
+const DEFAULT_ROUTES: &str = r#"[{"path": "/", "handler": "init"}]"#;
+
+/// What the integration does with a request matched by a route.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum RouteHandler {
+    /// Edge detect and inject the agent into the page.
+    #[serde(alias = "inject")]
+    Init,
+    /// Edge detect without injection.
+    EdgeDetect,
+    /// Look up bot detection results and send them to the origin.
+    Results,
+    /// Send to the origin without bot detection.
+    Bypass,
+    /// Respond with `403 Forbidden`.
+    Block,
+    /// Look up results like `Results`, but serve the challenge page if there is no request id
+    /// or the results are suspicious.
+    Challenge
+}
+
+#[derive(Deserialize)]
+struct RouteEntry {
+    /// Glob pattern, `*` matches within a path segment and `**` across segments.
+    path: Option<String>,
+    regex: Option<String>,
+    #[serde(default)]
+    methods: Vec<String>,
+    handler: RouteHandler
+}
+
+struct Route {
+    pattern: Regex,
+    methods: Vec<String>,
+    handler: RouteHandler
+}
+
+/// Ordered list of routes, the first route matching the request is used.
+pub struct RouteTable {
+    routes: Vec<Route>
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                re.push_str(".*");
+            },
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            _ => re.push_str(&regex::escape(&c.to_string()))
+        }
+    }
+    re.push('$');
+    re
+}
+
+impl Route {
+    fn from_entry(entry: RouteEntry) -> Result<Self, BotdError> {
+        let re = match (entry.path, entry.regex) {
+            (Some(p), None) => glob_to_regex(&p),
+            (None, Some(r)) => r,
+            _ => return Err(InvalidRoutes(String::from("route needs either path or regex")))
+        };
+        let pattern = Regex::new(&re).map_err(|_| RegexSyntax(re.to_owned()))?;
+        let methods = entry.methods.iter().map(|m| m.trim().to_uppercase()).collect();
+        Ok(Route { pattern, methods, handler: entry.handler })
+    }
+
+    fn matches(&self, req: &Request) -> bool {
+        let method_matches = self.methods.is_empty() || self.methods.iter().any(|m| m == req.get_method_str());
+        method_matches && self.pattern.is_match(req.get_path())
+    }
+}
+
+/// WebSocket handshake: `GET` with `Connection: upgrade` and `Upgrade: websocket`.
+pub fn is_upgrade_requested(req: &Request) -> bool {
+    let connection_upgrade = req.get_header_str(CONNECTION)
+        .map_or(false, |c| c.split(',').any(|t| t.trim().eq_ignore_ascii_case("upgrade")));
+    req.get_method() == Method::GET
+        && connection_upgrade
+        && req.get_header_str(UPGRADE).map_or(false, |u| u.trim().eq_ignore_ascii_case("websocket"))
+}
+
+impl RouteTable {
+    pub fn parse(value: Option<String>) -> Result<Self, BotdError> {
+        let value = value.unwrap_or_else(|| String::from(DEFAULT_ROUTES));
+        let entries: Vec<RouteEntry> = serde_json::from_str(&value).map_err(|e| InvalidRoutes(e.to_string()))?;
+        let routes = entries.into_iter().map(Route::from_entry).collect::<Result<Vec<Route>, BotdError>>()?;
+        log::debug!("[routes] Loaded {} routes", routes.len());
+        Ok(RouteTable { routes })
+    }
+
+    /// Handler of the first matching route, `None` if no route matches.
+    pub fn find(&self, req: &Request) -> Option<RouteHandler> {
+        self.routes.iter().find(|r| r.matches(req)).map(|r| r.handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(glob: &str, path: &str) -> bool {
+        Regex::new(&glob_to_regex(glob)).unwrap().is_match(path)
+    }
+
+    #[test]
+    fn single_star_matches_within_segment() {
+        assert_eq!(glob_to_regex("/api/*"), "^/api/[^/]*$");
+        assert!(matches("/api/*", "/api/users"));
+        assert!(matches("/api/*", "/api/"));
+        assert!(!matches("/api/*", "/api/users/1"));
+        assert!(!matches("/api/*", "/apis/users"));
+    }
+
+    #[test]
+    fn double_star_matches_across_segments() {
+        assert_eq!(glob_to_regex("/api/**"), "^/api/.*$");
+        assert!(matches("/api/**", "/api/users/1"));
+        assert!(matches("/**/edit", "/posts/1/edit"));
+        assert!(!matches("/**/edit", "/posts/1/editor"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(matches("/v?/", "/v1/"));
+        assert!(!matches("/v?/", "/v10/"));
+        assert!(!matches("/a?b", "/a/b"));
+    }
+
+    #[test]
+    fn other_characters_are_literal() {
+        assert!(matches("/index.html", "/index.html"));
+        assert!(!matches("/index.html", "/indexxhtml"));
+        assert!(matches("/a+b(c)[d]", "/a+b(c)[d]"));
+        assert!(!matches("/", "/page"));
+    }
+}
@@ -0,0 +1,92 @@
+use fastly::{Request, Response};
+use crate::config::Config;
+use crate::detector::RESULT_HEADERS;
+use crate::error::BotdError;
+use crate::utils::secure_eq;
+
+pub const DEBUG_SECRET_HEADER: &str = "botd-debug-secret";
+const DEBUG_ROUTE_HEADER: &str = "botd-debug-route";
+const DEBUG_REQUEST_ID_HEADER: &str = "botd-debug-request-id";
+const DEBUG_ERROR_HEADER: &str = "botd-debug-error";
+const DEBUG_DECISION_HEADER: &str = "botd-debug-decision";
+
+/// Collects what happened to a single request, so it can be returned to
+/// an authenticated client in `botd-debug-*` response headers.
+pub struct Trace {
+    enabled: bool,
+    route: &'static str,
+    req_id: Option<String>,
+    detection: Vec<(&'static str, String)>,
+    error: Option<String>,
+    decision: &'static str
+}
+
+impl Trace {
+    /// Removes the debug secret from the request, it must never reach the origin.
+    /// Has to be called before anything can forward the request, even before reading config.
+    pub fn take_secret(req: &mut Request) -> Option<String> {
+        req.remove_header_str(DEBUG_SECRET_HEADER)
+    }
+
+    pub fn from_secret(provided: Option<String>, config: &Config) -> Self {
+        let enabled = match (&config.debug_secret, provided) {
+            (Some(secret), Some(provided)) => secure_eq(secret, &provided),
+            _ => false
+        };
+        if enabled {
+            log::debug!("[trace] Debug trace enabled for request");
+        }
+        Trace { enabled, ..Self::disabled() }
+    }
+
+    pub fn disabled() -> Self {
+        Trace {
+            enabled: false,
+            route: "unknown",
+            req_id: None,
+            detection: Vec::new(),
+            error: None,
+            decision: "none"
+        }
+    }
+
+    pub fn route(&mut self, route: &'static str) {
+        self.route = route;
+    }
+
+    pub fn req_id(&mut self, req_id: &str) {
+        self.req_id = Some(String::from(req_id));
+    }
+
+    pub fn detection(&mut self, req: &Request) {
+        self.detection = RESULT_HEADERS.iter()
+            .filter_map(|name| req.get_header_str(*name).map(|v| (*name, String::from(v))))
+            .collect();
+    }
+
+    pub fn error(&mut self, err: &BotdError) {
+        self.error = Some(err.to_string());
+    }
+
+    pub fn decision(&mut self, decision: &'static str) {
+        self.decision = decision;
+    }
+
+    pub fn apply(&self, mut resp: Response) -> Response {
+        if !self.enabled {
+            return resp;
+        }
+        resp.set_header(DEBUG_ROUTE_HEADER, self.route);
+        resp.set_header(DEBUG_DECISION_HEADER, self.decision);
+        if let Some(id) = &self.req_id {
+            resp.set_header(DEBUG_REQUEST_ID_HEADER, id.as_str());
+        }
+        if let Some(e) = &self.error {
+            resp.set_header(DEBUG_ERROR_HEADER, e.as_str());
+        }
+        for (name, value) in self.detection.iter() {
+            resp.set_header(*name, value.as_str());
+        }
+        resp
+    }
+}
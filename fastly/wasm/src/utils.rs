@@ -1,7 +1,8 @@
 use fastly::Request;
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::convert::TryFrom;
-use cookie::{Cookie, SameSite};
+use cookie::Cookie;
+use crate::config::CookieConfig;
 use std::net::{Ipv4Addr, IpAddr};
 use IpAddr::V4;
 use psl::{Psl, List};
@@ -24,18 +25,31 @@ pub fn get_timestamp_ms() -> i64 {
     };
 }
 
-pub fn make_cookie(name: &str, value: String, domain: Option<String>) -> String {
-    let mut cookie = Cookie::build(name, value)
+pub fn make_cookie(name: &str, value: String, domain: Option<String>, max_age: Option<u64>, config: &CookieConfig) -> String {
+    let mut cookie = Cookie::build(config.full_name(name), value)
         .path("/")
-        .secure(true)
+        .secure(config.secure)
         .http_only(true)
-        .same_site(SameSite::None)
+        .same_site(config.same_site)
         .finish();
-    if let Some(d) = domain {
-        log::debug!("[cookie] Cookie domain set to: {}", d);
-        cookie.set_domain(d);
+    if !config.host_prefix && !config.host_only {
+        if let Some(d) = config.domain.to_owned().or(domain) {
+            log::debug!("[cookie] Cookie domain set to: {}", d);
+            cookie.set_domain(d);
+        }
+    }
+    let mut result = cookie.to_string();
+    if let Some(age) = max_age {
+        result.push_str(&format!("; Max-Age={}", age));
+    }
+    if config.partitioned {
+        result.push_str("; Partitioned");
     }
-    cookie.to_string()
+    result
+}
+
+pub fn make_request_id_cookie(req_id: String, domain: Option<String>, config: &CookieConfig) -> String {
+    make_cookie(&config.name, req_id, domain, config.max_age, config)
 }
 
 pub fn get_e_tld_plus_one(req: &Request) -> Option<String> {
@@ -63,43 +77,34 @@ pub fn is_ip(src: String) -> bool {
 pub fn get_ip(req: &Request) -> String {
     const CLIENT_IP_HEADER: &str = "Fastly-Client-IP";
     if let Some(ip) = req.get_header_str(CLIENT_IP_HEADER) {
-        let ip_str = String::from(ip);
-        log::debug!("[ip] Get ip address from Fastly-Client-IP header: {}", ip_str);
-        return ip_str
+        log::debug!("[ip] Get ip address from Fastly-Client-IP header");
+        return String::from(ip)
     }
     if let Some(ip) = req.get_client_ip_addr() {
-        let ip_str = ip.to_string();
-        log::debug!("[ip] Get ip address from request: {}", ip_str);
-        return ip_str
+        log::debug!("[ip] Get ip address from request");
+        return ip.to_string()
     }
     V4(Ipv4Addr::UNSPECIFIED).to_string()
 }
 
-pub fn is_static_requested(req: &Request) -> bool {
-    const SEC_FETCH_DEST_HEADER: &str = "sec-fetch-dest";
-    // TODO: add all static types
-    const STATIC_SEC_FETCH_DEST: [&str; 7] = ["font", "script", "image", "style", "video", "manifest", "object"];
-    // TODO: add all static types
-    const STATIC_PATH_ENDINGS: [&str; 7] = [".css", ".js", ".jpg", ".png", ".svg", ".jpeg", ".woff2"];
-    // sec-fetch-dest header shows which content was requested, but it works not in all web-browsers
-    let sec_fetch_dest_op = req.get_header(String::from(SEC_FETCH_DEST_HEADER));
-    if let Some(sec_fetch_dest) = sec_fetch_dest_op {
-        for s in STATIC_SEC_FETCH_DEST.iter() {
-            if sec_fetch_dest.eq(s) {
-                return true;
-            }
-        }
+pub fn secure_eq(a: &str, b: &str) -> bool {
+    // compare in constant time, so secrets can't be guessed byte by byte
+    if a.len() != b.len() {
         return false;
     }
-    // sec-fetch-dest header doesn't exist => check by path ending
-    for s in STATIC_PATH_ENDINGS.iter() {
-        if req.get_path().ends_with(s) {
-            return true;
-        }
-    }
-    false
+    a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
-pub fn is_favicon_requested(req: &Request) -> bool {
-    return is_static_requested(req) && req.get_path().ends_with(".ico");
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secure_eq_compares_whole_strings() {
+        assert!(secure_eq("secret", "secret"));
+        assert!(secure_eq("", ""));
+        assert!(!secure_eq("secret", "secreT"));
+        assert!(!secure_eq("secret", "secret1"));
+        assert!(!secure_eq("secret", ""));
+    }
+}
@@ -0,0 +1,153 @@
+use fastly::Request;
+use hmac::{Hmac, Mac, NewMac};
+use json::JsonValue;
+use sha2::Sha256;
+use crate::config::Config;
+use crate::detector::RESULT_HEADERS;
+use crate::protocol::RESULT_JSON_HEADER;
+use crate::request_id::{get_cookie, RequestId};
+use crate::utils::{get_timestamp_ms, make_cookie};
+use crate::{PROCESSED_STATUS, REQUEST_STATUS_HEADER};
+
+pub const VERDICT_COOKIE: &str = "botd-verdict";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Summary of bot detection results, which is stored in a signed cookie,
+/// so that next requests can be verified without calling botd.
+pub struct Verdict {
+    pub req_id: String,
+    expires: i64,
+    headers: Vec<(String, String)>
+}
+
+fn sign(payload: &str, key: &str) -> Option<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).ok()?;
+    mac.update(payload.as_bytes());
+    Some(mac)
+}
+
+impl Verdict {
+    /// Makes verdict from detection results transferred to the request headers.
+    /// Only processed results make a verdict. The whole JSON result isn't kept,
+    /// with it the cookie could exceed the browser limit of about 4 KB and be dropped.
+    pub fn from_req(req: &Request, req_id: &str, config: &Config) -> Option<Self> {
+        config.verdict_cookie_key.as_ref()?;
+        if req.get_header_str(REQUEST_STATUS_HEADER)? != PROCESSED_STATUS {
+            return None;
+        }
+        let headers = RESULT_HEADERS.iter()
+            .filter(|name| **name != RESULT_JSON_HEADER)
+            .filter_map(|name| Some((String::from(*name), String::from(req.get_header_str(*name)?))))
+            .collect();
+        let expires = get_timestamp_ms() + config.verdict_cookie_ttl as i64 * 1000;
+        Some(Verdict { req_id: String::from(req_id), expires, headers })
+    }
+
+    /// Reads verdict from the cookie, returns `None` if it's missing, tampered, expired
+    /// or was made for another request id than the one in the request.
+    pub fn verify(req: &Request, config: &Config) -> Option<Self> {
+        let key = config.verdict_cookie_key.as_ref()?;
+        let cookie = get_cookie(req, &config.cookie.full_name(VERDICT_COOKIE))?;
+        Self::check(&cookie, key, &RequestId::from_req(req, config).ok()?, get_timestamp_ms())
+    }
+
+    fn check(cookie: &str, key: &str, req_id: &str, now: i64) -> Option<Self> {
+        let verdict = Self::decode(cookie, key, now)?;
+        if req_id != verdict.req_id {
+            log::debug!("[verdict] Verdict cookie is made for another request id");
+            return None;
+        }
+        Some(verdict)
+    }
+
+    fn decode(cookie: &str, key: &str, now: i64) -> Option<Self> {
+        let mut parts = cookie.splitn(2, '.');
+        let payload = parts.next()?;
+        let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        if sign(payload, key)?.verify(&signature).is_err() {
+            log::error!("[verdict] Verdict cookie signature is invalid");
+            return None;
+        }
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+        let json = json::parse(std::str::from_utf8(&payload).ok()?).ok()?;
+        let expires = json["exp"].as_i64()?;
+        if expires < now {
+            log::debug!("[verdict] Verdict cookie is expired");
+            return None;
+        }
+        let headers = json["headers"].entries()
+            .filter_map(|(name, value)| Some((String::from(name), String::from(value.as_str()?))))
+            .collect();
+        Some(Verdict { req_id: String::from(json["id"].as_str()?), expires, headers })
+    }
+
+    /// Transfers verified results to the request headers, same as `transfer_headers` does for botd response.
+    pub fn transfer_headers(&self, req: &mut Request) {
+        for (name, value) in self.headers.iter() {
+            req.set_header(name.as_str(), value.as_str());
+        }
+    }
+
+    fn encode(&self, key: &str) -> Option<String> {
+        let mut json_headers = JsonValue::new_object();
+        for (name, value) in self.headers.iter() {
+            json_headers[name.as_str()] = value.as_str().into();
+        }
+        let mut json = JsonValue::new_object();
+        json["id"] = self.req_id.as_str().into();
+        json["exp"] = self.expires.into();
+        json["headers"] = json_headers;
+        let payload = base64::encode_config(json.dump(), base64::URL_SAFE_NO_PAD);
+        let signature = sign(&payload, key)?.finalize().into_bytes();
+        Some(format!("{}.{}", payload, base64::encode_config(signature, base64::URL_SAFE_NO_PAD)))
+    }
+
+    pub fn make_cookie(&self, domain: Option<String>, config: &Config) -> Option<String> {
+        let value = self.encode(config.verdict_cookie_key.as_ref()?)?;
+        Some(make_cookie(VERDICT_COOKIE, value, domain, Some(config.verdict_cookie_ttl), &config.cookie))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "verdict-key";
+    const NOW: i64 = 1_600_000_000_000;
+
+    fn cookie(req_id: &str, expires: i64) -> String {
+        let headers = vec![(String::from("botd-request-status"), String::from("processed"))];
+        Verdict { req_id: String::from(req_id), expires, headers }.encode(KEY).unwrap()
+    }
+
+    #[test]
+    fn valid_cookie_is_accepted() {
+        let verdict = Verdict::check(&cookie("abc", NOW + 1000), KEY, "abc", NOW).unwrap();
+        assert_eq!(verdict.req_id, "abc");
+        assert_eq!(verdict.expires, NOW + 1000);
+        assert_eq!(verdict.headers, vec![(String::from("botd-request-status"), String::from("processed"))]);
+    }
+
+    #[test]
+    fn tampered_cookie_is_rejected() {
+        let valid = cookie("abc", NOW + 1000);
+        let signature = valid.splitn(2, '.').nth(1).unwrap();
+        let payload = base64::encode_config(r#"{"id":"abc","exp":1600000001000,"headers":{"botd-request-status":"processed","botd-vm-status":"good"}}"#, base64::URL_SAFE_NO_PAD);
+        assert!(Verdict::check(&format!("{}.{}", payload, signature), KEY, "abc", NOW).is_none());
+        assert!(Verdict::check(&format!("{}x", valid), KEY, "abc", NOW).is_none());
+        assert!(Verdict::check(&valid, "another-key", "abc", NOW).is_none());
+        assert!(Verdict::check("abc", KEY, "abc", NOW).is_none());
+    }
+
+    #[test]
+    fn expired_cookie_is_rejected() {
+        assert!(Verdict::check(&cookie("abc", NOW - 1), KEY, "abc", NOW).is_none());
+        assert!(Verdict::check(&cookie("abc", NOW), KEY, "abc", NOW).is_some());
+    }
+
+    #[test]
+    fn cookie_for_another_request_id_is_rejected() {
+        assert!(Verdict::check(&cookie("abc", NOW + 1000), KEY, "abd", NOW).is_none());
+    }
+}